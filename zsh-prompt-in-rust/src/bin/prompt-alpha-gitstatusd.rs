@@ -0,0 +1,5 @@
+//! Serves repository locations to the prompt module; see `zsh_prompt_in_rust::daemon`.
+
+fn main() -> anyhow::Result<()> {
+    zsh_prompt_in_rust::daemon::run()
+}
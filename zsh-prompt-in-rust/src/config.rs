@@ -0,0 +1,391 @@
+use std::ffi::CStr;
+use std::sync::{LazyLock, Mutex};
+
+use crate::glyphs::Glyphs;
+use crate::path::Strategy;
+use crate::prompt::{Prompt, SEGMENTS};
+use crate::template::Template;
+use crate::theme::{Color, Depth, Palette, Role, Theme};
+use crate::title::Mode;
+
+pub struct Config {
+    /// Compute the location on a worker thread, rather than holding up the prompt for it.
+    pub async_location: bool,
+    /// Shown in place of the location while a worker is computing it; empty for an ellipsis.
+    pub pending: String,
+    /// How long the prompt may wait on the location before giving up on it, in milliseconds.
+    pub budget_ms: u32,
+    /// The same, for a worker computing the location in the background.
+    pub async_budget_ms: u32,
+    /// How old a lock file must be before we call it stale, in seconds.
+    pub stale_lock_secs: u32,
+    /// Names to show for particular repositories, keyed by root directory or remote URL.
+    pub aliases: Vec<(String, String)>,
+    /// Lengthen the names of repositories that would otherwise look the same; see `names`.
+    pub disambiguate: bool,
+    /// How to shorten directories that are too long, in the order to try them; `None` for the
+    /// default.
+    pub shorten: Option<Vec<Strategy>>,
+    /// How much of the line a directory may take up before it's shortened, in percent.
+    pub path_width_percent: u32,
+    /// Tell the terminal which directory we're in, with OSC 7.
+    pub report_cwd: bool,
+    /// Mark out prompts, command lines and output for the terminal, with OSC 133.
+    pub marks: bool,
+    /// Link the location to the directory, with OSC 8.
+    pub links: bool,
+    /// Link the repository's name to its remote's web page too.
+    pub remote_links: bool,
+    /// How to set the terminal's title, if at all.
+    pub title: Mode,
+    /// The title at the prompt; empty for the default.
+    pub title_format: String,
+    /// The title while a command runs; empty for the default.
+    pub title_command_format: String,
+    /// Whether to make `$PS1` fit the terminal: see `prompt::render`.
+    pub fit: bool,
+    /// Segments to leave out of `$PS1` when it won't fit, in the order to leave them out; `None`
+    /// for the default.
+    pub drop: Option<Vec<&'static str>>,
+    /// The one-line layout `$PS1` falls back on when nothing else fits; `None` for its default.
+    pub narrow_template: Option<Template>,
+    /// Each prompt's layout, in the order of `Prompt::ALL`; `None` for its default.
+    pub templates: [Option<Template>; Prompt::ALL.len()],
+    pub theme: Theme,
+    /// What to draw the prompt with; `None` to go by the locale.
+    pub glyphs: Option<&'static Glyphs>,
+    /// How many colors the terminal can show; `None` to go by its environment.
+    pub color_depth: Option<Depth>,
+}
+
+impl Config {
+    const fn new() -> Config {
+        Config {
+            async_location: true,
+            pending: String::new(),
+            budget_ms: 50,
+            async_budget_ms: 5000,
+            stale_lock_secs: 300,
+            aliases: Vec::new(),
+            disambiguate: true,
+            shorten: None,
+            path_width_percent: 40,
+            report_cwd: true,
+            marks: true,
+            links: true,
+            remote_links: false,
+            title: Mode::Auto,
+            title_format: String::new(),
+            title_command_format: String::new(),
+            fit: true,
+            drop: None,
+            narrow_template: None,
+            templates: [const { None }; Prompt::ALL.len()],
+            theme: Theme::DEFAULT,
+            glyphs: None,
+            color_depth: None,
+        }
+    }
+
+    pub fn title_format(&self) -> &str {
+        if self.title_format.is_empty() {
+            "zsh: {location}"
+        } else {
+            &self.title_format
+        }
+    }
+
+    pub fn title_command_format(&self) -> &str {
+        if self.title_command_format.is_empty() {
+            "{command}"
+        } else {
+            &self.title_command_format
+        }
+    }
+
+    pub fn shorten(&self) -> &[Strategy] {
+        self.shorten
+            .as_deref()
+            .unwrap_or(&[Strategy::Fish, Strategy::Ellipsis])
+    }
+
+    pub fn drop_order(&self) -> &[&'static str] {
+        self.drop
+            .as_deref()
+            .unwrap_or(&["clock", "venv", "timing", "host", "locks"])
+    }
+
+    /// `None` if `$PS1` isn't to be fitted to the terminal.
+    pub fn narrow_template(&self) -> Option<Template> {
+        static DEFAULT: LazyLock<Template> =
+            LazyLock::new(|| Template::parse("{location} {keymode}{symbol} ").unwrap());
+        self.fit.then(|| {
+            self.narrow_template
+                .clone()
+                .unwrap_or_else(|| DEFAULT.clone())
+        })
+    }
+
+    pub fn template(&self, which: Prompt) -> Template {
+        static DEFAULTS: LazyLock<Vec<Template>> = LazyLock::new(|| {
+            Prompt::ALL
+                .iter()
+                .map(|p| Template::parse(p.default_template()).unwrap())
+                .collect()
+        });
+        self.templates[which.index()]
+            .clone()
+            .unwrap_or_else(|| DEFAULTS[which.index()].clone())
+    }
+}
+
+pub static CONFIG: Mutex<Config> = Mutex::new(Config::new());
+
+/// `_rust-prompt-alpha_set OPTION VALUE…`
+pub fn set(args: &[&CStr]) -> Result<(), i32> {
+    let Some((option, values)) = args.split_first() else {
+        usage();
+        return Err(1);
+    };
+    let option = arg_str(option)?;
+    match option {
+        "watch" => {
+            let enabled = single_bool(option, values)?;
+            if let Err(err) = crate::watch::set_enabled(enabled) {
+                eprintln!("Could not start the repository watcher: {err}");
+                return Err(1);
+            }
+        }
+        "async" => CONFIG.lock().unwrap().async_location = single_bool(option, values)?,
+        "pending" => CONFIG.lock().unwrap().pending = single(option, values)?.to_owned(),
+        "budget" => CONFIG.lock().unwrap().budget_ms = single_u32(option, values)?,
+        "async-budget" => CONFIG.lock().unwrap().async_budget_ms = single_u32(option, values)?,
+        "stale-lock" => CONFIG.lock().unwrap().stale_lock_secs = single_u32(option, values)?,
+        "alias" => {
+            let [key, alias] = values else {
+                eprintln!("alias takes a repository (its path, or remote URL), and a name.");
+                return Err(1);
+            };
+            let (key, alias) = (arg_str(key)?.to_owned(), arg_str(alias)?.to_owned());
+            let aliases = &mut CONFIG.lock().unwrap().aliases;
+            aliases.retain(|(k, _)| *k != key);
+            aliases.push((key, alias));
+        }
+        "unalias" => {
+            let key = single(option, values)?;
+            CONFIG.lock().unwrap().aliases.retain(|(k, _)| k != key);
+        }
+        _ if Prompt::for_option(option).is_some() => {
+            let which = Prompt::for_option(option).unwrap();
+            let template = single(option, values)?;
+            // An empty one puts the default back.
+            CONFIG.lock().unwrap().templates[which.index()] = if template.is_empty() {
+                None
+            } else {
+                Some(parse_template(template)?)
+            };
+        }
+        "glyphs" => {
+            let name = single(option, values)?;
+            CONFIG.lock().unwrap().glyphs = match name {
+                "auto" => None,
+                _ => match Glyphs::named(name) {
+                    Some(glyphs) => Some(glyphs),
+                    None => {
+                        eprintln!("glyphs must be auto, ascii, unicode, or nerd.");
+                        return Err(1);
+                    }
+                },
+            };
+        }
+        "fit" => CONFIG.lock().unwrap().fit = single_bool(option, values)?,
+        "drop" => {
+            let mut names = Vec::new();
+            for value in values {
+                let value = arg_str(value)?;
+                let Some(segment) = SEGMENTS.iter().find(|s| s.name == value) else {
+                    eprintln!("There's no segment called {value:?}.");
+                    return Err(1);
+                };
+                names.push(segment.name);
+            }
+            CONFIG.lock().unwrap().drop = Some(names);
+        }
+        "narrow-template" => {
+            let template = single(option, values)?;
+            CONFIG.lock().unwrap().narrow_template = if template.is_empty() {
+                None
+            } else {
+                Some(parse_template(template)?)
+            };
+        }
+        "theme" => {
+            let name = single(option, values)?;
+            let Some(theme) = Theme::named(name) else {
+                eprintln!("There's no theme called {name:?}; there's default, and gruvbox.");
+                return Err(1);
+            };
+            CONFIG.lock().unwrap().theme = theme;
+            Palette::invalidate();
+        }
+        "color" => {
+            let [role, color] = values else {
+                eprintln!("color takes a role, and a color.");
+                return Err(1);
+            };
+            let Some(role) = Role::parse(arg_str(role)?) else {
+                eprintln!("Unknown role {role:?}.");
+                return Err(1);
+            };
+            let Some(color) = Color::parse(arg_str(color)?) else {
+                eprintln!(
+                    "A color is a name (red, bright-red, …), a number up to 255, or #rrggbb."
+                );
+                return Err(1);
+            };
+            CONFIG.lock().unwrap().theme.set(role, color);
+            Palette::invalidate();
+        }
+        "colors" => {
+            let depth = single(option, values)?;
+            CONFIG.lock().unwrap().color_depth = match depth {
+                "auto" => None,
+                _ => match Depth::parse(depth) {
+                    Some(d) => Some(d),
+                    None => {
+                        eprintln!("colors must be auto, none, 16, 256, or truecolor.");
+                        return Err(1);
+                    }
+                },
+            };
+            Palette::invalidate();
+        }
+        "disambiguate" => CONFIG.lock().unwrap().disambiguate = single_bool(option, values)?,
+        "shorten" => {
+            let mut strategies = Vec::new();
+            for value in values {
+                let value = arg_str(value)?;
+                let Some(strategy) = Strategy::parse(value) else {
+                    eprintln!(
+                        "Unknown way of shortening {value:?}; there's fish, unique, ellipsis, and \
+                         last:N."
+                    );
+                    return Err(1);
+                };
+                strategies.push(strategy);
+            }
+            // None at all means never shortening.
+            CONFIG.lock().unwrap().shorten = Some(strategies);
+        }
+        "report-cwd" => CONFIG.lock().unwrap().report_cwd = single_bool(option, values)?,
+        "marks" => CONFIG.lock().unwrap().marks = single_bool(option, values)?,
+        "links" => CONFIG.lock().unwrap().links = single_bool(option, values)?,
+        "remote-links" => CONFIG.lock().unwrap().remote_links = single_bool(option, values)?,
+        "title" => {
+            let Some(mode) = Mode::parse(single(option, values)?) else {
+                eprintln!("title must be off, auto, osc0, osc2, or screen.");
+                return Err(1);
+            };
+            CONFIG.lock().unwrap().title = mode;
+        }
+        "title-format" => CONFIG.lock().unwrap().title_format = single(option, values)?.to_owned(),
+        "title-command-format" => {
+            CONFIG.lock().unwrap().title_command_format = single(option, values)?.to_owned()
+        }
+        "path-width" => {
+            let percent = single_u32(option, values)?;
+            if 100 < percent {
+                eprintln!("path-width is a percentage of the line, up to 100.");
+                return Err(1);
+            }
+            CONFIG.lock().unwrap().path_width_percent = percent;
+        }
+        _ => {
+            eprintln!("Unknown option {option:?}.");
+            usage();
+            return Err(1);
+        }
+    }
+    Ok(())
+}
+
+fn usage() {
+    eprintln!(
+        "Usage:\n\
+         \t_rust-prompt-alpha_set watch on|off\n\
+         \t_rust-prompt-alpha_set async on|off\n\
+         \t_rust-prompt-alpha_set pending TEXT\n\
+         \t_rust-prompt-alpha_set budget MS\n\
+         \t_rust-prompt-alpha_set async-budget MS\n\
+         \t_rust-prompt-alpha_set stale-lock SECONDS\n\
+         \t_rust-prompt-alpha_set alias PATH|URL NAME\n\
+         \t_rust-prompt-alpha_set unalias PATH|URL\n\
+         \t_rust-prompt-alpha_set disambiguate on|off\n\
+         \t_rust-prompt-alpha_set shorten [fish|unique|ellipsis|last:N]…\n\
+         \t_rust-prompt-alpha_set path-width PERCENT\n\
+         \t_rust-prompt-alpha_set report-cwd on|off\n\
+         \t_rust-prompt-alpha_set marks on|off\n\
+         \t_rust-prompt-alpha_set links on|off\n\
+         \t_rust-prompt-alpha_set remote-links on|off\n\
+         \t_rust-prompt-alpha_set title off|auto|osc0|osc2|screen\n\
+         \t_rust-prompt-alpha_set title-format|title-command-format FORMAT\n\
+         \t_rust-prompt-alpha_set template TEMPLATE\n\
+         \t_rust-prompt-alpha_set rprompt-template|ps2-template|ps4-template|sprompt-template \
+         TEMPLATE\n\
+         \t_rust-prompt-alpha_set fit on|off\n\
+         \t_rust-prompt-alpha_set drop SEGMENT…\n\
+         \t_rust-prompt-alpha_set narrow-template TEMPLATE\n\
+         \t_rust-prompt-alpha_set theme default|gruvbox\n\
+         \t_rust-prompt-alpha_set color ROLE COLOR\n\
+         \t_rust-prompt-alpha_set glyphs auto|ascii|unicode|nerd\n\
+         \t_rust-prompt-alpha_set colors auto|none|16|256|truecolor"
+    );
+}
+
+fn arg_str(arg: &CStr) -> Result<&str, i32> {
+    match arg.to_str() {
+        Ok(s) => Ok(s),
+        Err(_err) => {
+            eprintln!("Could not covert argument to &str");
+            Err(1)
+        }
+    }
+}
+
+fn single<'a>(option: &str, values: &[&'a CStr]) -> Result<&'a str, i32> {
+    match values {
+        [value] => arg_str(value),
+        _ => {
+            eprintln!("{option} takes exactly one value.");
+            Err(1)
+        }
+    }
+}
+
+fn single_bool(option: &str, values: &[&CStr]) -> Result<bool, i32> {
+    match single(option, values)? {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => {
+            eprintln!("{option} must be on, or off.");
+            Err(1)
+        }
+    }
+}
+
+fn parse_template(template: &str) -> Result<Template, i32> {
+    Template::parse(template).map_err(|err| {
+        eprintln!("Bad template: {err}.");
+        1
+    })
+}
+
+fn single_u32(option: &str, values: &[&CStr]) -> Result<u32, i32> {
+    match single(option, values)?.parse::<u32>() {
+        Ok(v) => Ok(v),
+        Err(_err) => {
+            eprintln!("{option} must be an unsigned integer.");
+            Err(1)
+        }
+    }
+}
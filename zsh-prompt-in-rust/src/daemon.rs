@@ -0,0 +1,547 @@
+//! A long-lived, per-user helper that caches the locations of recently visited repositories, and
+//! serves them to every shell over a Unix socket in `$XDG_RUNTIME_DIR`.
+//!
+//! What's cached is the finished location, by working directory, and it's kept only for as long as
+//! the repository's watcher says nothing has changed; on any change, the next query works the
+//! location out from scratch, `git` processes and all. (Unlike `gitstatusd`, there's no index held
+//! in memory, to be updated piecemeal.) Every repository is watched by the one `Watcher`, and so the
+//! one inotify instance.
+//!
+//! The module asks the daemon first; if there's no daemon, it does the work itself.
+//!
+//! The protocol is one query per connection. The client sends the milliseconds it is willing to
+//! wait and its working directory, each NUL-terminated, and shuts down its write half. The daemon
+//! answers `+` as soon as a worker takes the query up, then the location, as a series of
+//! NUL-terminated fields (see `encode`), and hangs up.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::location::{get_location, Location, Repo};
+use crate::process::TimedOut;
+use crate::vcs::{Head, Kind, Lock, Operation, StateDir, Unsquashed};
+use crate::watch::{Target, WatchId, Watcher};
+
+/// How many repositories the daemon keeps locations (and watches) for at once.
+const MAX_REPOS: usize = 32;
+
+/// How many queries are answered at once. A query can hang on a slow filesystem, so there's more
+/// than one; but not one per connection, or a hung mount would pile up threads without end.
+const WORKERS: usize = 4;
+
+/// How many connections wait for a worker before more are turned away. A shell that's turned away
+/// just works the location out itself.
+const QUEUE: usize = 16;
+
+/// How long a client waits for a worker to take its query up. Any longer, and the daemon is busy
+/// enough that the shell is better off working the location out itself.
+const PICKUP_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// What a worker sends first, on taking a query up.
+const PICKED_UP: u8 = b'+';
+
+/// How long the daemon waits on a client to send its query.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn socket_path() -> Option<PathBuf> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")?;
+    if runtime_dir.is_empty() {
+        return None;
+    }
+    let mut path = PathBuf::from(runtime_dir);
+    path.push("zsh-prompt-in-rust");
+    path.push("gitstatus.sock");
+    Some(path)
+}
+
+/// Asks the daemon for the location of `cwd`. `None` if there's no daemon to ask, or it's too busy
+/// to answer.
+pub fn query(cwd: &Path, deadline: Instant) -> Option<Location> {
+    let path = socket_path()?;
+    let mut stream = UnixStream::connect(path).ok()?;
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Some(Location::TimedOut);
+    }
+    stream.set_write_timeout(Some(remaining)).ok()?;
+
+    let mut request = Vec::new();
+    put(&mut request, remaining.as_millis().to_string().as_bytes());
+    put(&mut request, cwd.as_os_str().as_bytes());
+    send_all(&stream, &request).ok()?;
+    stream.shutdown(std::net::Shutdown::Write).ok()?;
+
+    // A query that's still queued might sit there for the whole budget, behind ones that hang;
+    // that's no timeout of the repository's, so don't count it as one.
+    let pickup = PICKUP_TIMEOUT.min(deadline.saturating_duration_since(Instant::now()));
+    stream.set_read_timeout(Some(pickup)).ok()?;
+    let mut ack = [0u8];
+    match stream.read(&mut ack) {
+        Ok(1) if ack[0] == PICKED_UP => (),
+        _ => return None,
+    }
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Some(Location::TimedOut);
+    }
+    stream.set_read_timeout(Some(remaining)).ok()?;
+    let mut reply = Vec::new();
+    match stream.read_to_end(&mut reply) {
+        Ok(_) => decode(&reply),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            Some(Location::TimedOut)
+        }
+        Err(_) => None,
+    }
+}
+
+/// Writes `buf` to the socket. `write()` would raise `SIGPIPE` if the daemon went away, and this
+/// runs inside of the shell, which we'd rather not kill.
+fn send_all(stream: &UnixStream, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        let n = unsafe {
+            libc::send(
+                stream.as_raw_fd(),
+                buf.as_ptr().cast(),
+                buf.len(),
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        buf = &buf[n as usize..];
+    }
+    Ok(())
+}
+
+fn put(buf: &mut Vec<u8>, field: &[u8]) {
+    buf.extend(field.iter().map(|&b| if b == 0 { b'?' } else { b }));
+    buf.push(0);
+}
+
+fn put_err(buf: &mut Vec<u8>, err: &anyhow::Error) {
+    if err.is::<TimedOut>() {
+        put(buf, b"timed-out");
+    } else {
+        put(buf, b"err");
+        put(buf, err.to_string().as_bytes());
+    }
+}
+
+fn put_result<T>(buf: &mut Vec<u8>, result: &anyhow::Result<T>, f: impl FnOnce(&mut Vec<u8>, &T)) {
+    match result {
+        Ok(v) => {
+            put(buf, b"ok");
+            f(buf, v);
+        }
+        Err(err) => put_err(buf, err),
+    }
+}
+
+fn encode(location: &Location) -> Vec<u8> {
+    let mut buf = Vec::new();
+    match location {
+        Location::Repo(repo) => {
+            put(&mut buf, b"repo");
+            put(&mut buf, repo.kind.as_str().as_bytes());
+            put(&mut buf, repo.root.as_os_str().as_bytes());
+            put(&mut buf, repo.state_dirs.len().to_string().as_bytes());
+            for dir in &repo.state_dirs {
+                put(&mut buf, dir.path.as_os_str().as_bytes());
+                put(&mut buf, if dir.recursive { b"1" } else { b"0" });
+            }
+            put(&mut buf, repo.name.as_bytes());
+            put(&mut buf, repo.remote.as_deref().unwrap_or("").as_bytes());
+            put_result(&mut buf, &repo.head, |buf, head| match head {
+                Head::Branch(b) => {
+                    put(buf, b"branch");
+                    put(buf, b);
+                }
+                Head::Detached(s) => {
+                    put(buf, b"detached");
+                    put(buf, s);
+                }
+                Head::Hg {
+                    branch,
+                    bookmark,
+                    revision,
+                } => {
+                    put(buf, b"hg");
+                    put(buf, branch);
+                    put(buf, bookmark.as_deref().unwrap_or(b""));
+                    put(buf, revision.as_bytes());
+                }
+                Head::Jj {
+                    change,
+                    bookmarks,
+                    parent_bookmarks,
+                    conflict,
+                } => {
+                    put(buf, b"jj");
+                    put(buf, change.as_bytes());
+                    put(buf, bookmarks.join(" ").as_bytes());
+                    put(buf, parent_bookmarks.join(" ").as_bytes());
+                    put(buf, if *conflict { b"1" } else { b"0" });
+                }
+            });
+            put_result(&mut buf, &repo.dirty, |buf, dirty| {
+                let dirty: &[u8] = match dirty {
+                    Some(true) => b"1",
+                    Some(false) => b"0",
+                    None => b"",
+                };
+                put(buf, dirty);
+            });
+            put_result(&mut buf, &repo.locks, |buf, locks| {
+                put(buf, locks.len().to_string().as_bytes());
+                for lock in locks {
+                    let since = lock.since.duration_since(UNIX_EPOCH).unwrap_or_default();
+                    put(buf, lock.path.as_os_str().as_bytes());
+                    put(buf, since.as_secs().to_string().as_bytes());
+                }
+            });
+            put_result(&mut buf, &repo.unsquashed, |buf, n| {
+                put(buf, n.map(|n| n.to_string()).unwrap_or_default().as_bytes());
+            });
+            put_result(&mut buf, &repo.operation, |buf, op| {
+                put(buf, op.map(|op| op.as_str()).unwrap_or("").as_bytes());
+            });
+            put_result(&mut buf, &repo.prefix, |buf, p| {
+                put(buf, p.as_os_str().as_bytes());
+            });
+            put_result(&mut buf, &repo.ignored, |buf, ignored| {
+                put(buf, if *ignored { b"1" } else { b"0" });
+            });
+        }
+        Location::NotRepo => put(&mut buf, b"not-repo"),
+        Location::Pending | Location::TimedOut | Location::Disabled => put(&mut buf, b"timed-out"),
+    }
+    buf
+}
+
+struct Fields<'a>(std::slice::Split<'a, u8, fn(&u8) -> bool>);
+
+impl<'a> Fields<'a> {
+    fn new(buf: &'a [u8]) -> Fields<'a> {
+        Fields(buf.split(|&b| b == 0))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        self.0.next()
+    }
+
+    fn str(&mut self) -> Option<&'a str> {
+        std::str::from_utf8(self.bytes()?).ok()
+    }
+
+    /// A space-separated list.
+    fn words(&mut self) -> Option<Vec<String>> {
+        Some(
+            self.str()?
+                .split(' ')
+                .filter(|w| !w.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        )
+    }
+
+    fn path(&mut self) -> Option<PathBuf> {
+        Some(PathBuf::from(OsStr::from_bytes(self.bytes()?)))
+    }
+
+    /// The other half of `put_result`.
+    fn result<T>(&mut self, f: impl FnOnce(&mut Self) -> Option<T>) -> Option<anyhow::Result<T>> {
+        match self.str()? {
+            "ok" => Some(Ok(f(self)?)),
+            "timed-out" => Some(Err(TimedOut.into())),
+            "err" => Some(Err(anyhow::anyhow!("{}", self.str()?))),
+            _ => None,
+        }
+    }
+}
+
+fn decode(buf: &[u8]) -> Option<Location> {
+    let mut fields = Fields::new(buf);
+    match fields.str()? {
+        "repo" => {
+            let kind = Kind::parse(fields.str()?)?;
+            let root = fields.path()?;
+            let n = fields.str()?.parse::<usize>().ok()?;
+            let state_dirs = (0..n)
+                .map(|_| {
+                    Some(StateDir {
+                        path: fields.path()?,
+                        recursive: fields.str()? == "1",
+                    })
+                })
+                .collect::<Option<_>>()?;
+            let name = fields.str()?.to_owned();
+            let remote = Some(fields.str()?)
+                .filter(|r| !r.is_empty())
+                .map(str::to_owned);
+            let head = fields.result(|fields| match fields.str()? {
+                "branch" => Some(Head::Branch(fields.bytes()?.to_owned())),
+                "detached" => Some(Head::Detached(fields.bytes()?.to_owned())),
+                "hg" => Some(Head::Hg {
+                    branch: fields.bytes()?.to_owned(),
+                    bookmark: Some(fields.bytes()?)
+                        .filter(|b| !b.is_empty())
+                        .map(<[u8]>::to_owned),
+                    revision: fields.str()?.to_owned(),
+                }),
+                "jj" => Some(Head::Jj {
+                    change: fields.str()?.to_owned(),
+                    bookmarks: fields.words()?,
+                    parent_bookmarks: fields.words()?,
+                    conflict: fields.str()? == "1",
+                }),
+                _ => None,
+            })?;
+            let dirty = fields.result(|fields| match fields.str()? {
+                "1" => Some(Some(true)),
+                "0" => Some(Some(false)),
+                _ => Some(None),
+            })?;
+            let locks = fields.result(|fields| {
+                let n = fields.str()?.parse::<usize>().ok()?;
+                (0..n)
+                    .map(|_| {
+                        let path = fields.path()?;
+                        let since = fields.str()?.parse().ok()?;
+                        Some(Lock {
+                            path,
+                            since: UNIX_EPOCH + Duration::from_secs(since),
+                        })
+                    })
+                    .collect()
+            })?;
+            let unsquashed = fields.result(|fields| match fields.str()? {
+                "" => Some(None),
+                n => {
+                    let (count, more) = match n.strip_suffix('+') {
+                        Some(count) => (count, true),
+                        None => (n, false),
+                    };
+                    let count = count.parse().ok()?;
+                    Some(Some(Unsquashed { count, more }))
+                }
+            })?;
+            let operation = fields.result(|fields| match fields.str()? {
+                "" => Some(None),
+                op => Some(Some(Operation::parse(op)?)),
+            })?;
+            let prefix = fields.result(|fields| fields.path())?;
+            let ignored = fields.result(|fields| Some(fields.str()? == "1"))?;
+            Some(Location::Repo(Box::new(Repo {
+                kind,
+                root,
+                state_dirs,
+                name,
+                remote,
+                head,
+                dirty,
+                unsquashed,
+                operation,
+                locks,
+                prefix,
+                ignored,
+            })))
+        }
+        "not-repo" => Some(Location::NotRepo),
+        "timed-out" => Some(Location::TimedOut),
+        _ => None,
+    }
+}
+
+struct WarmRepo {
+    watch: WatchId,
+    /// Encoded locations, by working directory; all of these are under this repository.
+    locations: HashMap<PathBuf, Vec<u8>>,
+    last_used: Instant,
+}
+
+struct State {
+    watcher: Watcher,
+    repos: HashMap<PathBuf, WarmRepo>,
+    /// Which repository a working directory is in, for those we've seen.
+    roots: HashMap<PathBuf, PathBuf>,
+}
+
+impl State {
+    fn new() -> anyhow::Result<State> {
+        Ok(State {
+            watcher: Watcher::start()?,
+            repos: HashMap::new(),
+            roots: HashMap::new(),
+        })
+    }
+
+    fn lookup(&mut self, cwd: &Path) -> Option<Vec<u8>> {
+        let root = self.roots.get(cwd)?;
+        let repo = self.repos.get_mut(root)?;
+        repo.last_used = Instant::now();
+        if !self.watcher.take_clean(repo.watch) {
+            repo.locations.clear();
+            return None;
+        }
+        repo.locations.get(cwd).cloned()
+    }
+
+    fn insert(&mut self, cwd: &Path, location: &Location, encoded: &[u8]) {
+        let Location::Repo(repo) = location else {
+            return;
+        };
+        let Repo {
+            kind,
+            root,
+            state_dirs,
+            ..
+        } = &**repo;
+        if location.timed_out() {
+            return;
+        }
+        if !self.repos.contains_key(root) {
+            self.evict();
+            let watch = self.watcher.watch(Target {
+                kind: *kind,
+                state_dirs: state_dirs.clone(),
+                worktree: root.clone(),
+            });
+            self.repos.insert(
+                root.clone(),
+                WarmRepo {
+                    watch,
+                    locations: HashMap::new(),
+                    last_used: Instant::now(),
+                },
+            );
+        }
+        let repo = self.repos.get_mut(root).unwrap();
+        repo.locations.insert(cwd.to_owned(), encoded.to_owned());
+        self.roots.insert(cwd.to_owned(), root.clone());
+    }
+
+    /// Makes room for one more repository.
+    fn evict(&mut self) {
+        while MAX_REPOS <= self.repos.len() {
+            let oldest = self
+                .repos
+                .iter()
+                .min_by_key(|(_, repo)| repo.last_used)
+                .map(|(root, _)| root.clone())
+                .unwrap();
+            if let Some(repo) = self.repos.remove(&oldest) {
+                self.watcher.unwatch(repo.watch);
+            }
+            self.roots.retain(|_, root| *root != oldest);
+        }
+    }
+}
+
+fn serve(mut stream: UnixStream, state: &Mutex<State>) -> anyhow::Result<()> {
+    // If the client has given up on us already, this fails, and there's no work to do.
+    send_all(&stream, &[PICKED_UP])?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = Vec::new();
+    stream.read_to_end(&mut request)?;
+    let mut fields = Fields::new(&request);
+    let budget_ms = match fields.str() {
+        Some(ms) => ms.parse::<u64>()?,
+        None => anyhow::bail!("malformed request"),
+    };
+    let cwd = match fields.path() {
+        Some(cwd) if cwd.is_absolute() => cwd,
+        _ => anyhow::bail!("malformed request"),
+    };
+    let deadline = Instant::now() + Duration::from_millis(budget_ms);
+
+    let cached = state.lock().unwrap().lookup(&cwd);
+    let reply = match cached {
+        Some(reply) => reply,
+        None => {
+            let location = get_location(&cwd, deadline, None);
+            let reply = encode(&location);
+            state.lock().unwrap().insert(&cwd, &location, &reply);
+            reply
+        }
+    };
+    send_all(&stream, &reply)?;
+    Ok(())
+}
+
+/// Runs the daemon; this doesn't return, save for errors setting up the socket.
+pub fn run() -> anyhow::Result<()> {
+    let Some(path) = socket_path() else {
+        anyhow::bail!("$XDG_RUNTIME_DIR isn't set; don't know where to put the socket");
+    };
+    let dir = path.parent().unwrap();
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    // A socket left over from a previous daemon is in the way; but if it is still answering,
+    // there's a daemon running already.
+    if let Ok(meta) = fs::symlink_metadata(&path) {
+        if UnixStream::connect(&path).is_ok() {
+            anyhow::bail!("a daemon is already listening on {}", path.display());
+        }
+        if meta.file_type().is_socket() {
+            fs::remove_file(&path)?;
+        }
+    }
+    let listener = UnixListener::bind(&path)?;
+
+    let state = Arc::new(Mutex::new(State::new()?));
+    let (tx, rx) = mpsc::sync_channel::<UnixStream>(QUEUE);
+    let rx = Arc::new(Mutex::new(rx));
+    for _ in 0..WORKERS {
+        let state = state.clone();
+        let rx = rx.clone();
+        std::thread::Builder::new()
+            .name("gitstatusd-query".to_owned())
+            .spawn(move || work(&rx, &state))?;
+    }
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        // If every worker is stuck, dropping the connection sends the shell off to do it itself.
+        let _ = tx.try_send(stream);
+    }
+    Ok(())
+}
+
+/// Answers queries as they come in, for as long as the daemon runs.
+fn work(rx: &Mutex<Receiver<UnixStream>>, state: &Mutex<State>) {
+    loop {
+        // Only held while waiting, so the next query goes to whichever worker is free.
+        let stream = match rx.lock().unwrap().recv() {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let _ = serve(stream, state);
+    }
+}
@@ -0,0 +1,229 @@
+//! Showing text we didn't write, as itself.
+//!
+//! Branch names, directory names, `$KEYMAP` and the like can hold anything at all. Written into the
+//! prompt as they are, a `%` in one is a zsh prompt escape (a branch called `%F{red}x` would turn
+//! the prompt red), and a control character is a message to the terminal (an `ESC ] 0 ;` retitles
+//! the window, and worse). So before any of it is shown:
+//!
+//! * `%` is doubled, so zsh shows it as it is;
+//! * control characters, C0 (and DEL) and C1 alike, are shown the way `cat -v` shows them: `^[` for
+//!   ESC, `^?` for DEL, `M-^[` for CSI;
+//! * bytes that aren't UTF-8 are shown as `\xNN`, rather than as a replacement character, so that
+//!   two names that differ only there still look different.
+
+use std::borrow::Cow;
+use std::fmt::{self, Write as _};
+
+/// `bytes`, as text: where they aren't UTF-8, each byte that isn't is written as `\xNN`.
+pub fn decode(bytes: &[u8]) -> Cow<'_, str> {
+    let mut chunks = bytes.utf8_chunks();
+    match chunks.next() {
+        None => return Cow::Borrowed(""),
+        Some(chunk) if chunk.invalid().is_empty() => return Cow::Borrowed(chunk.valid()),
+        Some(_) => {}
+    }
+    let mut out = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        out.push_str(chunk.valid());
+        for b in chunk.invalid() {
+            let _ = write!(out, "\\x{b:02x}");
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// `s`, with its control characters made visible, for where `%` means nothing (a title, say).
+pub fn controls(s: &str) -> Cow<'_, str> {
+    if !s.chars().any(is_control) {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        push_visible(&mut out, c);
+    }
+    Cow::Owned(out)
+}
+
+/// `s`, to be shown as it is in a zsh prompt: with `%` doubled, and its control characters made
+/// visible.
+pub fn prompt(s: &str) -> Cow<'_, str> {
+    if !s.chars().any(|c| c == '%' || is_control(c)) {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len() + 8);
+    for c in s.chars() {
+        match c {
+            '%' => out.push_str("%%"),
+            c => push_visible(&mut out, c),
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Shows `.0` in a prompt as itself; see `prompt`.
+pub struct Untrusted<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Untrusted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Most of what goes through here is a plain `str`, but a name gets formatted into a
+        // larger message often enough that it's simplest to format first, and escape after.
+        f.write_str(&prompt(&self.0.to_string()))
+    }
+}
+
+fn is_control(c: char) -> bool {
+    matches!(c, '\0'..='\x1f' | '\x7f'..='\u{9f}')
+}
+
+fn push_visible(out: &mut String, c: char) {
+    match c {
+        '\0'..='\x1f' => {
+            out.push('^');
+            out.push(char::from(c as u8 + 0x40));
+        }
+        '\x7f' => out.push_str("^?"),
+        '\u{80}'..='\u{9f}' => {
+            out.push_str("M-^");
+            out.push(char::from(c as u8 - 0x80 + 0x40));
+        }
+        c => out.push(c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What zsh shows for `prompt`, assuming the only escape in it is `%%`.
+    fn shown(prompt: &str) -> String {
+        prompt.replace("%%", "%")
+    }
+
+    /// Whatever was put in, nothing's left that zsh or the terminal would act on.
+    fn assert_inert(escaped: &str) {
+        assert!(
+            !escaped.chars().any(is_control),
+            "control character in {escaped:?}"
+        );
+        let mut chars = escaped.chars();
+        while let Some(c) = chars.next() {
+            if c == '%' {
+                assert_eq!(chars.next(), Some('%'), "lone % in {escaped:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn plain_text_is_left_alone() {
+        for s in ["main", "feature/thing-1", "±☿⋈ 漢字 🙂", ""] {
+            assert!(matches!(prompt(s), Cow::Borrowed(_)));
+            assert_eq!(prompt(s), s);
+        }
+    }
+
+    #[test]
+    fn percent_is_doubled() {
+        assert_eq!(prompt("%F{red}x"), "%%F{red}x");
+        assert_eq!(prompt("100%"), "100%%");
+        assert_eq!(prompt("%%"), "%%%%");
+        assert_eq!(prompt("%{\x1b]0;pwned\x07%}"), "%%{^[]0;pwned^G%%}");
+    }
+
+    #[test]
+    fn prompt_escapes_show_as_written() {
+        for s in [
+            "%F{red}x",
+            "%B%U%S",
+            "%(?.ok.%F{red}fail)",
+            "%{%}",
+            "%D{%H}",
+            "%~%n%m%M%#",
+            "trailing%",
+            "%%%",
+        ] {
+            let escaped = prompt(s);
+            assert_inert(&escaped);
+            assert_eq!(shown(&escaped), s);
+            // And zsh would give it as much room as the text itself takes.
+            let width = crate::width::lines(&escaped, &|_| 99);
+            assert_eq!(width, vec![s.chars().count()], "{s:?}");
+        }
+    }
+
+    #[test]
+    fn c0_controls_are_visible() {
+        assert_eq!(prompt("a\x1b]0;title\x07b"), "a^[]0;title^Gb");
+        assert_eq!(prompt("\x1b[31mred\x1b[0m"), "^[[31mred^[[0m");
+        assert_eq!(prompt("line\nbreak\r\t"), "line^Jbreak^M^I");
+        assert_eq!(prompt("\0"), "^@");
+        assert_eq!(prompt("\x1f"), "^_");
+        assert_eq!(prompt("\x7f"), "^?");
+    }
+
+    #[test]
+    fn c1_controls_are_visible() {
+        // CSI, OSC and ST, which some terminals act on just like ESC [, ESC ] and ESC \.
+        assert_eq!(prompt("\u{9b}31m"), "M-^[31m");
+        assert_eq!(prompt("\u{9d}0;x\u{9c}"), "M-^]0;xM-^\\");
+        assert_eq!(prompt("\u{80}"), "M-^@");
+        assert_eq!(prompt("\u{9f}"), "M-^_");
+        // Just past them: a no-break space is nothing special.
+        assert_eq!(prompt("\u{a0}"), "\u{a0}");
+    }
+
+    #[test]
+    fn every_control_character_is_escaped() {
+        for c in ('\0'..='\u{ff}').filter(|c| is_control(*c)) {
+            let s = format!("x{c}%y");
+            let escaped = prompt(&s);
+            assert_inert(&escaped);
+            assert!(escaped.starts_with('x') && escaped.ends_with("%%y"));
+            assert!(!controls(&s).chars().any(is_control));
+        }
+    }
+
+    #[test]
+    fn controls_leaves_percent_alone() {
+        assert_eq!(controls("50% \x1b\\ done"), "50% ^[\\ done");
+        assert!(matches!(controls("%F{red}"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn invalid_utf8_is_shown_as_bytes() {
+        assert_eq!(decode(b"main"), "main");
+        assert!(matches!(decode(b"caf\xc3\xa9"), Cow::Borrowed("café")));
+        assert_eq!(decode(b"caf\xe9"), "caf\\xe9");
+        assert_eq!(decode(b"\xff\xfe"), "\\xff\\xfe");
+        // A sequence cut short, and a lone continuation byte.
+        assert_eq!(decode(b"a\xe2\x82b\x80"), "a\\xe2\\x82b\\x80");
+        assert_eq!(decode(b""), "");
+        // Two names that differ only in their invalid bytes still look different.
+        assert_ne!(decode(b"x\xfe"), decode(b"x\xff"));
+    }
+
+    #[test]
+    fn invalid_utf8_cant_smuggle_controls() {
+        // Overlong encodings of ESC and `%`, and a C1 control as raw Latin-1.
+        for bytes in [
+            &b"\xc0\x9b"[..],
+            b"\xc0\xa5F{red}",
+            b"\x9b31m",
+            b"\xe0\x80\x9b",
+        ] {
+            let escaped = prompt(&decode(bytes)).into_owned();
+            assert_inert(&escaped);
+            assert!(escaped.contains("\\x"), "{escaped:?}");
+        }
+    }
+
+    #[test]
+    fn untrusted_escapes_what_it_formats() {
+        let branch = "%F{red}\x1b]0;x\x07";
+        assert_eq!(Untrusted(branch).to_string(), "%%F{red}^[]0;x^G");
+        let detached = format_args!("(detached HEAD: {branch})").to_string();
+        assert_eq!(
+            Untrusted(&detached).to_string(),
+            "(detached HEAD: %%F{red}^[]0;x^G)"
+        );
+    }
+}
@@ -1,79 +1,396 @@
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::os::unix::ffi::OsStringExt;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::Instant;
 
-pub fn show_toplevel() -> Option<PathBuf> {
+use crate::process::{output_until, TimedOut};
+use crate::vcs::{Backend, Head, Kind, Lock, Operation, StateDir, Unsquashed};
+
+/// The most commits we'll look through for fixups; a branch that's this far ahead of its upstream
+/// has bigger problems.
+const MAX_COMMITS_WALKED: u32 = 256;
+
+/// How many repositories' fixup counts are kept; the daemon looks at more than one.
+const MAX_COUNTS_KEPT: usize = 32;
+
+/// A count of fixups, and the two commits it was counted between.
+struct Counted {
+    root: PathBuf,
+    head: Vec<u8>,
+    base: Vec<u8>,
+    unsquashed: Unsquashed,
+}
+
+/// The last few counts, oldest first. Anything changing in the worktree means the location is
+/// worked out again, but the count only changes when HEAD or its upstream moves.
+static COUNTED: Mutex<Vec<Counted>> = Mutex::new(Vec::new());
+
+pub struct Git;
+
+impl Backend for Git {
+    fn kind(&self) -> Kind {
+        Kind::Git
+    }
+
+    fn discover(&self, dir: &Path, deadline: Instant) -> Result<Option<PathBuf>, TimedOut> {
+        show_toplevel(dir, deadline)
+    }
+
+    fn state_dirs(&self, root: &Path) -> Vec<StateDir> {
+        // HEAD and the index live directly in the git dir, and branches under refs/; but in a
+        // linked worktree, refs/ (and packed-refs) are in the common dir, along with the other
+        // worktrees' and the main one's.
+        let git_dir = git_dir(root);
+        let common_dir = common_dir(&git_dir);
+        let mut dirs = vec![StateDir::new(&git_dir)];
+        if common_dir != git_dir {
+            dirs.push(StateDir::new(&common_dir));
+        }
+        dirs.push(StateDir::recursive(&common_dir.join("refs")));
+        dirs
+    }
+
+    fn head(&self, root: &Path, _deadline: Instant) -> anyhow::Result<Head> {
+        get_branch(root)
+    }
+
+    fn dirty(&self, root: &Path, deadline: Instant) -> anyhow::Result<Option<bool>> {
+        let mut cmd = Command::new("git");
+        // Without --no-optional-locks, status refreshes the index, which the watcher would then
+        // see as a change.
+        cmd.current_dir(root)
+            .arg("--no-optional-locks")
+            .arg("status")
+            .arg("--porcelain")
+            .arg("--untracked-files=no")
+            .stderr(Stdio::null());
+        let output = output_until(&mut cmd, deadline)?;
+        if !output.status.success() {
+            anyhow::bail!("git status failed");
+        }
+        Ok(Some(!output.stdout.is_empty()))
+    }
+
+    fn ignored(&self, root: &Path, prefix: &Path, deadline: Instant) -> anyhow::Result<bool> {
+        if prefix.as_os_str().is_empty() {
+            return Ok(false);
+        }
+        // This also finds directories that are only ignored by way of a parent.
+        let mut cmd = Command::new("git");
+        cmd.current_dir(root)
+            .arg("check-ignore")
+            .arg("--quiet")
+            .arg("--")
+            .arg(prefix)
+            .stderr(Stdio::null());
+        let output = output_until(&mut cmd, deadline)?;
+        match output.status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => anyhow::bail!("git check-ignore failed"),
+        }
+    }
+
+    fn ignored_dirs(&self, root: &Path, deadline: Instant) -> anyhow::Result<Vec<PathBuf>> {
+        // With `--directory`, a directory that's ignored as a whole is listed once, with a `/`,
+        // rather than file by file.
+        let mut cmd = Command::new("git");
+        cmd.current_dir(root)
+            .args([
+                "ls-files",
+                "-z",
+                "--others",
+                "--ignored",
+                "--exclude-standard",
+            ])
+            .arg("--directory")
+            .stderr(Stdio::null());
+        let output = output_until(&mut cmd, deadline)?;
+        if !output.status.success() {
+            anyhow::bail!("git ls-files failed");
+        }
+        let dirs = output
+            .stdout
+            .split(|&b| b == 0)
+            .filter_map(|entry| entry.strip_suffix(b"/"))
+            .map(|dir| root.join(OsStr::from_bytes(dir)))
+            .collect();
+        Ok(dirs)
+    }
+
+    fn unsquashed(&self, root: &Path, deadline: Instant) -> anyhow::Result<Option<Unsquashed>> {
+        let Some(base) = upstream(root) else {
+            return Ok(None);
+        };
+        let git_dir = git_dir(root);
+        let ends = resolve_ref(&git_dir, b"HEAD").zip(resolve_ref(&git_dir, base.as_bytes()));
+        if let Some((head, base)) = &ends {
+            let counted = COUNTED.lock().unwrap();
+            let same = |c: &&Counted| c.root == root && c.head == *head && c.base == *base;
+            if let Some(counted) = counted.iter().find(same) {
+                return Ok(Some(counted.unsquashed));
+            }
+        }
+        let mut cmd = Command::new("git");
+        cmd.current_dir(root)
+            .arg("log")
+            .arg(format!("--max-count={MAX_COMMITS_WALKED}"))
+            .arg("--format=%s")
+            .arg("HEAD")
+            .arg("--not")
+            .arg(base)
+            .arg("--")
+            .stderr(Stdio::null());
+        let output = output_until(&mut cmd, deadline)?;
+        if !output.status.success() {
+            anyhow::bail!("git log failed");
+        }
+        let subjects = String::from_utf8_lossy(&output.stdout);
+        let unsquashed = Unsquashed {
+            count: subjects.lines().filter(|s| is_unsquashed(s)).count() as u32,
+            more: subjects.lines().count() as u32 == MAX_COMMITS_WALKED,
+        };
+        // Without both commits to key it on, there's no telling when it goes stale.
+        if let Some((head, base)) = ends {
+            let mut counted = COUNTED.lock().unwrap();
+            counted.retain(|c| c.root != root);
+            if MAX_COUNTS_KEPT <= counted.len() {
+                counted.remove(0);
+            }
+            counted.push(Counted {
+                root: root.to_owned(),
+                head,
+                base,
+                unsquashed,
+            });
+        }
+        Ok(Some(unsquashed))
+    }
+
+    fn operation(&self, root: &Path) -> anyhow::Result<Option<Operation>> {
+        let git_dir = git_dir(root);
+        let exists = |name: &str| git_dir.join(name).try_exists();
+        let op = if exists("rebase-merge")? {
+            Some(Operation::Rebase)
+        } else if exists("rebase-apply")? {
+            if exists("rebase-apply/applying")? {
+                Some(Operation::Am)
+            } else {
+                Some(Operation::Rebase)
+            }
+        } else if exists("MERGE_HEAD")? {
+            Some(Operation::Merge)
+        } else if exists("CHERRY_PICK_HEAD")? {
+            Some(Operation::CherryPick)
+        } else if exists("REVERT_HEAD")? {
+            Some(Operation::Revert)
+        } else if exists("BISECT_LOG")? {
+            Some(Operation::Bisect)
+        } else {
+            None
+        };
+        Ok(op)
+    }
+
+    fn locks(&self, root: &Path) -> anyhow::Result<Vec<Lock>> {
+        let git_dir = git_dir(root);
+        let common_dir = common_dir(&git_dir);
+        let mut candidates = vec![
+            (&git_dir, PathBuf::from("index.lock")),
+            (&git_dir, PathBuf::from("HEAD.lock")),
+            (&common_dir, PathBuf::from("packed-refs.lock")),
+        ];
+        // Of the loose refs, only the branch we're on: a lock left on any other is out of the way
+        // until it's checked out, and looking through all of refs/ on every prompt could take a
+        // while in a repository with plenty of remotes and tags.
+        if let Ok(Head::Branch(branch)) = get_branch(root) {
+            let path = [b"refs/heads/", &branch[..], b".lock"].concat();
+            candidates.push((&common_dir, PathBuf::from(OsString::from_vec(path))));
+        }
+        let mut locks = Vec::new();
+        for (dir, path) in candidates {
+            match fs::symlink_metadata(dir.join(&path)) {
+                Ok(meta) => locks.push(Lock {
+                    path,
+                    since: meta.modified()?,
+                }),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(locks)
+    }
+
+    fn remote(&self, root: &Path) -> Option<String> {
+        remote_url(&common_dir(&git_dir(root)))
+    }
+}
+
+/// The URL of `origin`, or failing that, of whichever remote comes first, from the config in
+/// `git_dir`.
+pub fn remote_url(git_dir: &Path) -> Option<String> {
+    let config = fs::read_to_string(git_dir.join("config")).ok()?;
+    let mut urls = crate::vcs::config_entries(&config)
+        .filter(|(section, key, _)| {
+            section.starts_with("remote ") && key.eq_ignore_ascii_case("url")
+        })
+        .map(|(section, _, url)| (section, url));
+    let first = urls.next()?;
+    let origin = std::iter::once(first)
+        .chain(urls)
+        .find(|(section, _)| *section == "remote \"origin\"");
+    Some(origin.unwrap_or(first).1.to_owned())
+}
+
+/// What HEAD will eventually be pushed on top of: the current branch's upstream, or, without one,
+/// `origin`'s default branch.
+fn upstream(root: &Path) -> Option<OsString> {
+    let common_dir = common_dir(&git_dir(root));
+    if let Ok(Head::Branch(branch)) = get_branch(root) {
+        // As bytes: the branch is named in here just as it is in HEAD, UTF-8 or not.
+        let config = fs::read(common_dir.join("config")).unwrap_or_default();
+        let section = [b"branch \"", &branch[..], b"\""].concat();
+        let (mut remote, mut merge) = (None, None);
+        for (s, key, value) in crate::vcs::config_entries_bytes(&config) {
+            if s == section && key.eq_ignore_ascii_case(b"remote") {
+                remote = Some(value);
+            } else if s == section && key.eq_ignore_ascii_case(b"merge") {
+                merge = Some(value);
+            }
+        }
+        if let (Some(remote), Some(merge)) = (remote, merge) {
+            let base = match (remote, merge.strip_prefix(b"refs/heads/")) {
+                // Tracking another local branch.
+                (b".", _) => merge.to_owned(),
+                (remote, Some(branch)) => [b"refs/remotes/", remote, b"/", branch].concat(),
+                (_, None) => return None,
+            };
+            return Some(OsString::from_vec(base));
+        }
+    }
+    // Symbolic refs are never packed, so if this exists, it's here.
+    if common_dir.join("refs/remotes/origin/HEAD").is_file() {
+        return Some("refs/remotes/origin/HEAD".into());
+    }
+    None
+}
+
+/// The commit `name` (`HEAD`, or a full ref name) points to, read the way git would: loose refs
+/// first, then `packed-refs`, following symbolic refs on the way. `None` if it can't be read that
+/// way, as in a repository that keeps its refs in a reftable.
+fn resolve_ref(git_dir: &Path, name: &[u8]) -> Option<Vec<u8>> {
+    let common_dir = common_dir(git_dir);
+    let mut name = name.to_owned();
+    // git gives up on symbolic refs nested deeper than this, too.
+    for _ in 0..5 {
+        // HEAD, and the few other refs a worktree has of its own, are in its git dir; everything
+        // else is in the common dir.
+        let loose = [git_dir, &common_dir]
+            .iter()
+            .find_map(|dir| fs::read(dir.join(OsStr::from_bytes(&name))).ok());
+        let Some(contents) = loose else {
+            return packed_ref(&common_dir, &name);
+        };
+        let contents = contents.strip_suffix(b"\n")?;
+        match contents.strip_prefix(b"ref: ") {
+            Some(target) => name = target.to_owned(),
+            None => return Some(contents.to_owned()),
+        }
+    }
+    None
+}
+
+/// The commit `name` points to, according to `packed-refs`.
+fn packed_ref(common_dir: &Path, name: &[u8]) -> Option<Vec<u8>> {
+    let packed = fs::read(common_dir.join("packed-refs")).ok()?;
+    // Each line is `<oid> <name>`, save for the header (`#`) and peeled tags (`^<oid>`).
+    packed
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.starts_with(b"#") && !line.starts_with(b"^"))
+        .find_map(|line| {
+            let space = line.iter().position(|&b| b == b' ')?;
+            (&line[space + 1..] == name).then(|| line[..space].to_owned())
+        })
+}
+
+/// Whether a commit with this subject is one `git rebase --autosquash` would squash, or one that
+/// says it isn't done yet.
+fn is_unsquashed(subject: &str) -> bool {
+    if ["fixup! ", "squash! ", "amend! "]
+        .iter()
+        .any(|p| subject.starts_with(p))
+    {
+        return true;
+    }
+    let mut words = subject.split(|c: char| !c.is_alphanumeric());
+    words.next().is_some_and(|w| w.eq_ignore_ascii_case("wip"))
+}
+
+/// A linked worktree's git dir holds only its own state (its HEAD, and so on); what it has in
+/// common with the main worktree, config included, is elsewhere.
+fn common_dir(git_dir: &Path) -> PathBuf {
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(dir) => git_dir.join(dir.trim_end_matches('\n')),
+        Err(_) => git_dir.to_owned(),
+    }
+}
+
+pub fn show_toplevel(dir: &Path, deadline: Instant) -> Result<Option<PathBuf>, TimedOut> {
     let mut cmd = Command::new("git");
-    cmd.arg("rev-parse")
+    cmd.current_dir(dir)
+        .arg("rev-parse")
         .arg("--show-toplevel")
-        .stderr(Stdio::null())
-        .stdout(Stdio::piped());
-    let child = match cmd.spawn() {
-        Ok(c) => c,
-        Err(_) => return None,
-    };
-    let output = match child.wait_with_output() {
+        .stderr(Stdio::null());
+    let output = match output_until(&mut cmd, deadline) {
         Ok(o) => o,
-        Err(_) => return None,
+        Err(err) if err.is::<TimedOut>() => return Err(TimedOut),
+        Err(_) => return Ok(None),
     };
     if !output.status.success() {
-        return None;
+        return Ok(None);
     }
 
     let mut output = output.stdout;
     if output.last().copied() == Some(b'\n') {
         output.pop();
     }
-    Some(PathBuf::from(OsString::from_vec(output)))
+    Ok(Some(PathBuf::from(OsString::from_vec(output))))
 }
 
-pub fn show_prefix() -> anyhow::Result<PathBuf> {
-    let mut cmd = Command::new("git");
-    cmd.arg("rev-parse")
-        .arg("--show-prefix")
-        .stderr(Stdio::null())
-        .stdout(Stdio::piped());
-    let output = cmd.spawn()?.wait_with_output()?;
-    if !output.status.success() {
-        anyhow::bail!("git rev-parse --show-prefix failed");
-    }
-
-    let mut output = output.stdout;
-    if output.last().copied() == Some(b'\n') {
-        output.pop();
+/// Finds the git dir of the worktree at `repository_root`, following a `.git` file (as used by
+/// `git worktree` and submodules) if there is one.
+pub fn git_dir(repository_root: &Path) -> PathBuf {
+    let dot_git = repository_root.join(".git");
+    if let Ok(contents) = fs::read_to_string(&dot_git) {
+        if let Some(dir) = contents.strip_prefix("gitdir: ") {
+            return repository_root.join(dir.trim_end_matches('\n'));
+        }
     }
-    Ok(PathBuf::from(OsString::from_vec(output)))
+    dot_git
 }
 
-pub fn get_branch(repository_root: &Path) -> anyhow::Result<GitHead> {
-    let head_path = {
-        let mut p = repository_root.join(".git");
-        p.push("HEAD");
-        p
-    };
-    let mut contents = fs::read_to_string(head_path)?;
-    if !contents.ends_with('\n') {
+pub fn get_branch(repository_root: &Path) -> anyhow::Result<Head> {
+    let head_path = git_dir(repository_root).join("HEAD");
+    // A branch's name is whatever bytes it was made with, UTF-8 or not.
+    let mut contents = fs::read(head_path)?;
+    if contents.pop() != Some(b'\n') {
         anyhow::bail!("HEAD didn't end in NL?");
     }
-    contents.pop();
-    if let Some(gref) = contents.strip_prefix("ref: ") {
-        if let Some(branch) = gref.strip_prefix("refs/heads/") {
-            Ok(GitHead::Branch(branch.to_owned()))
+    if let Some(gref) = contents.strip_prefix(b"ref: ") {
+        if let Some(branch) = gref.strip_prefix(b"refs/heads/") {
+            Ok(Head::Branch(branch.to_owned()))
         } else {
             anyhow::bail!("HEAD is a ref, but not a branch?")
         }
     } else {
-        Ok(GitHead::Detached(contents))
+        Ok(Head::Detached(contents))
     }
 }
 
-pub enum GitHead {
-    Branch(String),
-    Detached(String),
-}
-
 /*
 pub fn get_branch() -> Result<String, ()> {
     let mut cmd = Command::new("git");
@@ -0,0 +1,90 @@
+//! The symbols the prompt is drawn with, in sets for what the terminal (and its font) can show.
+//!
+//! Unless told otherwise, we go by the locale: `$LC_ALL`, `$LC_CTYPE` or `$LANG`, whichever is set
+//! first, as the C library would. Where that isn't UTF-8 (the C locale on a serial console, say,
+//! or `LANG=C` over SSH), anything past ASCII comes out as garbage, so we stick to ASCII.
+
+use crate::config::CONFIG;
+
+pub struct Glyphs {
+    pub git: &'static str,
+    pub hg: &'static str,
+    pub jj: &'static str,
+    /// Before how long the last command took.
+    pub timer: &'static str,
+    /// Before how a failed command exited.
+    pub failed: &'static str,
+    /// Where to type the command.
+    pub symbol: &'static str,
+    /// Stands in for whatever's been left out.
+    pub ellipsis: &'static str,
+}
+
+impl Glyphs {
+    pub const ASCII: Glyphs = Glyphs {
+        git: "git",
+        hg: "hg",
+        jj: "jj",
+        timer: "t:",
+        failed: "!",
+        symbol: ">",
+        ellipsis: "...",
+    };
+
+    /// The symbols this prompt has always had.
+    pub const UNICODE: Glyphs = Glyphs {
+        git: "±",
+        hg: "☿",
+        jj: "⋈",
+        timer: "⏱",
+        failed: "✘",
+        symbol: "»",
+        ellipsis: "…",
+    };
+
+    /// For Nerd Fonts (and other Powerline-patched ones), from their private use area.
+    pub const NERD: Glyphs = Glyphs {
+        // nf-dev-git, nf-fa-mercury, nf-oct-git_compare.
+        git: "\u{e702}",
+        hg: "\u{f223}",
+        jj: "\u{f47f}",
+        // nf-fa-clock_o, nf-fa-times, and Powerline's thin right arrow.
+        timer: "\u{f017} ",
+        failed: "\u{f00d} ",
+        symbol: "\u{e0b1}",
+        ellipsis: "…",
+    };
+
+    pub fn named(name: &str) -> Option<&'static Glyphs> {
+        match name {
+            "ascii" => Some(&Glyphs::ASCII),
+            "unicode" => Some(&Glyphs::UNICODE),
+            "nerd" => Some(&Glyphs::NERD),
+            _ => None,
+        }
+    }
+}
+
+/// The set to draw with: whichever's been picked, or else whichever suits the locale.
+pub fn get() -> &'static Glyphs {
+    if let Some(glyphs) = CONFIG.lock().unwrap().glyphs {
+        return glyphs;
+    }
+    let param = |name| {
+        crate::zsh::param_bytes(name)
+            .map(|b| String::from_utf8_lossy(&b).into_owned())
+            .filter(|s| !s.is_empty())
+    };
+    let locale = param(c"LC_ALL")
+        .or_else(|| param(c"LC_CTYPE"))
+        .or_else(|| param(c"LANG"))
+        .unwrap_or_default();
+    // `en_US.UTF-8`, `C.utf8`, and the like; with none at all, it's the C locale.
+    let codeset = locale.split_once('.').map_or("", |(_, c)| c);
+    let codeset = codeset.split_once('@').map_or(codeset, |(c, _)| c);
+    if matches!(codeset.to_ascii_lowercase().as_str(), "utf-8" | "utf8") {
+        &Glyphs::UNICODE
+    } else {
+        &Glyphs::ASCII
+    }
+}
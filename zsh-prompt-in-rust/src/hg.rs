@@ -0,0 +1,155 @@
+//! Mercurial working copies, read straight out of `.hg/`: `hg` itself takes far too long to start
+//! to be run on every prompt.
+
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::process::TimedOut;
+use crate::vcs::{Backend, Head, Kind, Lock, Operation, StateDir, Unsquashed};
+
+pub struct Hg;
+
+impl Backend for Hg {
+    fn kind(&self) -> Kind {
+        Kind::Hg
+    }
+
+    fn discover(&self, dir: &Path, _deadline: Instant) -> Result<Option<PathBuf>, TimedOut> {
+        Ok(dir
+            .ancestors()
+            .find(|d| d.join(".hg").is_dir())
+            .map(Path::to_owned))
+    }
+
+    fn state_dirs(&self, root: &Path) -> Vec<StateDir> {
+        // Everything we read is directly in `.hg/`.
+        vec![StateDir::new(&root.join(".hg"))]
+    }
+
+    fn head(&self, root: &Path, _deadline: Instant) -> anyhow::Result<Head> {
+        let hg = root.join(".hg");
+        let branch = match read_trimmed(&hg.join("branch"))? {
+            Some(b) if !b.is_empty() => b,
+            // A working copy that has never been on any other branch has no branch file.
+            _ => b"default".to_vec(),
+        };
+        let bookmark = read_trimmed(&hg.join("bookmarks.current"))?.filter(|b| !b.is_empty());
+        let (p1, _p2) = dirstate_parents(&hg)?;
+        Ok(Head::Hg {
+            branch,
+            bookmark,
+            revision: short_hex(&p1),
+        })
+    }
+
+    fn dirty(&self, _root: &Path, _deadline: Instant) -> anyhow::Result<Option<bool>> {
+        // That takes comparing the dirstate against the working copy, which is `hg status`'s job.
+        Ok(None)
+    }
+
+    fn ignored(&self, _root: &Path, _prefix: &Path, _deadline: Instant) -> anyhow::Result<bool> {
+        // .hgignore has its own syntaxes, and they aren't worth an `hg` for.
+        Ok(false)
+    }
+
+    fn ignored_dirs(&self, _root: &Path, _deadline: Instant) -> anyhow::Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    fn unsquashed(&self, _root: &Path, _deadline: Instant) -> anyhow::Result<Option<Unsquashed>> {
+        Ok(None)
+    }
+
+    fn operation(&self, root: &Path) -> anyhow::Result<Option<Operation>> {
+        let hg = root.join(".hg");
+        let exists = |name: &str| hg.join(name).try_exists();
+        let op = if exists("rebasestate")? {
+            Some(Operation::Rebase)
+        } else if exists("histedit-state")? {
+            Some(Operation::Histedit)
+        } else if exists("merge")? {
+            Some(Operation::Merge)
+        } else {
+            // An uncommitted merge without conflicts may have no merge state, but it does have a
+            // second parent.
+            let (_p1, p2) = dirstate_parents(&hg)?;
+            if p2.iter().any(|&b| b != 0) {
+                Some(Operation::Merge)
+            } else {
+                None
+            }
+        };
+        Ok(op)
+    }
+
+    fn locks(&self, _root: &Path) -> anyhow::Result<Vec<Lock>> {
+        Ok(Vec::new())
+    }
+
+    fn remote(&self, root: &Path) -> Option<String> {
+        let hgrc = fs::read_to_string(root.join(".hg").join("hgrc")).ok()?;
+        let (_, _, url) = crate::vcs::config_entries(&hgrc)
+            .find(|(section, key, _)| *section == "paths" && *key == "default")?;
+        Some(url.to_owned())
+    }
+}
+
+/// Reads a small state file, without its trailing newline. `None` if there's no such file. As
+/// bytes: branches and bookmarks are named in whatever encoding `hg` was run in.
+fn read_trimmed(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(mut contents) => {
+            if contents.last() == Some(&b'\n') {
+                contents.pop();
+            }
+            Ok(Some(contents))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+type Node = [u8; 20];
+
+/// The working copy's parents, from the head of `.hg/dirstate`. In the original format, the file
+/// starts with the two 20-byte nodes; in v2, it starts with a marker, then the two nodes, each
+/// padded out to 32 bytes.
+fn dirstate_parents(hg: &Path) -> anyhow::Result<(Node, Node)> {
+    const V2_MARKER: &[u8] = b"dirstate-v2\n";
+
+    let mut header = [0u8; V2_MARKER.len() + 64];
+    let mut len = 0;
+    let mut file = match fs::File::open(hg.join("dirstate")) {
+        Ok(f) => f,
+        // A fresh repository with nothing checked out.
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(([0; 20], [0; 20])),
+        Err(err) => return Err(err.into()),
+    };
+    while len < header.len() {
+        match file.read(&mut header[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    let header = &header[..len];
+
+    let (p1, p2) = if let Some(rest) = header.strip_prefix(V2_MARKER) {
+        if rest.len() < 64 {
+            anyhow::bail!("dirstate-v2 header is truncated");
+        }
+        (&rest[..20], &rest[32..52])
+    } else {
+        if header.len() < 40 {
+            anyhow::bail!("dirstate header is truncated");
+        }
+        (&header[..20], &header[20..40])
+    };
+    Ok((p1.try_into().unwrap(), p2.try_into().unwrap()))
+}
+
+/// The 12-digit abbreviation `hg` itself uses.
+fn short_hex(node: &Node) -> String {
+    node[..6].iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,147 @@
+//! Jujutsu workspaces, including those colocated with a git repository.
+//!
+//! jj's state lives in its operation log and commit store, which we're not about to parse; so
+//! unlike git and Mercurial, this asks `jj` itself. It is told not to snapshot the working copy,
+//! which keeps it fast, and keeps the prompt from creating operations.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+use crate::process::{output_until, TimedOut};
+use crate::vcs::{Backend, Head, Kind, Lock, Operation, StateDir, Unsquashed};
+
+/// One line per commit: whether it's `@`, then its change ID, local bookmarks, and whether it
+/// has conflicts; tab-separated.
+const TEMPLATE: &str = r#"if(current_working_copy, "@", "-") ++ "\t" ++ change_id.shortest(8) ++ "\t" ++ local_bookmarks.join(" ") ++ "\t" ++ if(conflict, "1", "0") ++ "\n""#;
+
+pub struct Jj;
+
+impl Backend for Jj {
+    fn kind(&self) -> Kind {
+        Kind::Jj
+    }
+
+    fn discover(&self, dir: &Path, _deadline: Instant) -> Result<Option<PathBuf>, TimedOut> {
+        Ok(dir
+            .ancestors()
+            .find(|d| d.join(".jj").is_dir())
+            .map(Path::to_owned))
+    }
+
+    fn state_dirs(&self, root: &Path) -> Vec<StateDir> {
+        // Every jj command that changes anything (`jj new`, `jj describe`, `jj bookmark`, …)
+        // finishes by replacing the head of the operation log; and snapshotting the working copy
+        // rewrites its state. Neither is anywhere near `.jj/` itself.
+        vec![
+            StateDir::new(&repo_dir(root).join("op_heads/heads")),
+            StateDir::new(&root.join(".jj/working_copy")),
+        ]
+    }
+
+    fn head(&self, root: &Path, deadline: Instant) -> anyhow::Result<Head> {
+        let mut cmd = Command::new("jj");
+        cmd.current_dir(root)
+            .arg("--ignore-working-copy")
+            .arg("--no-pager")
+            .arg("--color=never")
+            .arg("log")
+            .arg("--no-graph")
+            .arg("-r")
+            .arg("@ | @-")
+            .arg("-T")
+            .arg(TEMPLATE)
+            .stderr(Stdio::null());
+        let output = output_until(&mut cmd, deadline)?;
+        if !output.status.success() {
+            anyhow::bail!("jj log failed");
+        }
+        let output = String::from_utf8(output.stdout)?;
+
+        let mut change = None;
+        let mut bookmarks = Vec::new();
+        let mut parent_bookmarks = Vec::new();
+        let mut conflict = false;
+        for line in output.lines() {
+            let mut fields = line.split('\t');
+            let (Some(which), Some(change_id), Some(names), Some(conflicted)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                anyhow::bail!("unexpected output from jj log: {line:?}");
+            };
+            let names = names
+                .split(' ')
+                .filter(|n| !n.is_empty())
+                .map(str::to_owned);
+            if which == "@" {
+                change = Some(change_id.to_owned());
+                bookmarks.extend(names);
+                conflict = conflicted == "1";
+            } else {
+                parent_bookmarks.extend(names);
+            }
+        }
+        let Some(change) = change else {
+            anyhow::bail!("jj log didn't show the working-copy commit");
+        };
+        Ok(Head::Jj {
+            change,
+            bookmarks,
+            parent_bookmarks,
+            conflict,
+        })
+    }
+
+    fn dirty(&self, _root: &Path, _deadline: Instant) -> anyhow::Result<Option<bool>> {
+        // The working copy is itself a commit; whether it has changes is only known once it's
+        // been snapshotted, which we've asked jj not to do.
+        Ok(None)
+    }
+
+    fn ignored(&self, _root: &Path, _prefix: &Path, _deadline: Instant) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    fn ignored_dirs(&self, root: &Path, deadline: Instant) -> anyhow::Result<Vec<PathBuf>> {
+        // jj goes by the same .gitignore files; colocated with git, git can list what they cover.
+        if root.join(".git").exists() {
+            return crate::git::Git.ignored_dirs(root, deadline);
+        }
+        Ok(Vec::new())
+    }
+
+    fn unsquashed(&self, _root: &Path, _deadline: Instant) -> anyhow::Result<Option<Unsquashed>> {
+        // There's no squashing later in jj; `jj squash` is what you'd do instead of a fixup.
+        Ok(None)
+    }
+
+    fn operation(&self, _root: &Path) -> anyhow::Result<Option<Operation>> {
+        // jj records conflicts in commits, rather than stopping partway through an operation.
+        Ok(None)
+    }
+
+    fn locks(&self, _root: &Path) -> anyhow::Result<Vec<Lock>> {
+        // jj's own locks are held only briefly, and it recovers from a crash on its own.
+        Ok(Vec::new())
+    }
+
+    fn remote(&self, root: &Path) -> Option<String> {
+        // jj keeps its commits in a git repository, and its remotes in that repository's config.
+        // The repository is either colocated, or tucked away in jj's store; either way, the store
+        // says where.
+        let store = repo_dir(root).join("store");
+        let git_target = fs::read_to_string(store.join("git_target")).ok()?;
+        crate::git::remote_url(&store.join(git_target.trim_end_matches('\n')))
+    }
+}
+
+/// The repository a workspace belongs to: `.jj/repo`, or in a secondary workspace, wherever that
+/// says the real one is.
+fn repo_dir(root: &Path) -> PathBuf {
+    let dot_jj = root.join(".jj");
+    match fs::read_to_string(dot_jj.join("repo")) {
+        Ok(path) => dot_jj.join(path),
+        Err(_) => dot_jj.join("repo"),
+    }
+}
@@ -3,8 +3,28 @@ use std::ops::DerefMut;
 use std::ptr::null_mut;
 use std::sync::Mutex;
 
+use prompt::Prompt;
+
+mod config;
+pub mod daemon;
+mod escape;
 mod git;
+mod glyphs;
+mod hg;
+mod jj;
+mod location;
+mod names;
+mod osc;
+mod path;
+mod process;
 mod prompt;
+mod template;
+mod theme;
+mod title;
+mod vcs;
+mod watch;
+mod width;
+mod worker;
 mod zsh;
 
 /// … the zsh API annoyingly requires all strings to be mutable. So, this holds their
@@ -59,6 +79,70 @@ impl OurModuleData {
                 defopts: null_mut(),
             });
         }
+        {
+            let builtin_name = strings.add(c"_rust-prompt-alpha_rprompt");
+            builtins_table.push(zsh::Builtin {
+                node: zsh::HashNode {
+                    next: null_mut(),
+                    nam: builtin_name,
+                    flags: 0,
+                },
+                handlerfunc: Some(rust_prompt_rprompt),
+                minargs: 0,
+                maxargs: -1,
+                funcid: 0,
+                optstr: null_mut(),
+                defopts: null_mut(),
+            });
+        }
+        {
+            let builtin_name = strings.add(c"_rust-prompt-alpha_ps2");
+            builtins_table.push(zsh::Builtin {
+                node: zsh::HashNode {
+                    next: null_mut(),
+                    nam: builtin_name,
+                    flags: 0,
+                },
+                handlerfunc: Some(rust_prompt_ps2),
+                minargs: 0,
+                maxargs: -1,
+                funcid: 0,
+                optstr: null_mut(),
+                defopts: null_mut(),
+            });
+        }
+        {
+            let builtin_name = strings.add(c"_rust-prompt-alpha_ps4");
+            builtins_table.push(zsh::Builtin {
+                node: zsh::HashNode {
+                    next: null_mut(),
+                    nam: builtin_name,
+                    flags: 0,
+                },
+                handlerfunc: Some(rust_prompt_ps4),
+                minargs: 0,
+                maxargs: -1,
+                funcid: 0,
+                optstr: null_mut(),
+                defopts: null_mut(),
+            });
+        }
+        {
+            let builtin_name = strings.add(c"_rust-prompt-alpha_sprompt");
+            builtins_table.push(zsh::Builtin {
+                node: zsh::HashNode {
+                    next: null_mut(),
+                    nam: builtin_name,
+                    flags: 0,
+                },
+                handlerfunc: Some(rust_prompt_sprompt),
+                minargs: 0,
+                maxargs: -1,
+                funcid: 0,
+                optstr: null_mut(),
+                defopts: null_mut(),
+            });
+        }
         {
             let builtin_name = strings.add(c"_rust-prompt-alpha_pre-exec");
             builtins_table.push(zsh::Builtin {
@@ -107,6 +191,38 @@ impl OurModuleData {
                 defopts: null_mut(),
             });
         }
+        {
+            let builtin_name = strings.add(c"_rust-prompt-alpha_async-done");
+            builtins_table.push(zsh::Builtin {
+                node: zsh::HashNode {
+                    next: null_mut(),
+                    nam: builtin_name,
+                    flags: 0,
+                },
+                handlerfunc: Some(rust_prompt_async_done),
+                minargs: 0,
+                maxargs: -1,
+                funcid: 0,
+                optstr: null_mut(),
+                defopts: null_mut(),
+            });
+        }
+        {
+            let builtin_name = strings.add(c"_rust-prompt-alpha_set");
+            builtins_table.push(zsh::Builtin {
+                node: zsh::HashNode {
+                    next: null_mut(),
+                    nam: builtin_name,
+                    flags: 0,
+                },
+                handlerfunc: Some(rust_prompt_set),
+                minargs: 0,
+                maxargs: -1,
+                funcid: 0,
+                optstr: null_mut(),
+                defopts: null_mut(),
+            });
+        }
 
         let features = zsh::Features {
             bn_list: builtins_table.as_mut_ptr(),
@@ -160,7 +276,63 @@ unsafe extern "C" fn rust_prompt(
     opts: *mut zsh::Options,
     q: libc::c_int,
 ) -> libc::c_int {
-    unsafe { zsh_fn_thunk(builtin_name, args, opts, q, prompt::prompt) }
+    unsafe {
+        zsh_fn_thunk(builtin_name, args, opts, q, |args| {
+            prompt::show(Prompt::Ps1, args)
+        })
+    }
+}
+
+unsafe extern "C" fn rust_prompt_rprompt(
+    builtin_name: *mut libc::c_char,
+    args: *mut *mut libc::c_char,
+    opts: *mut zsh::Options,
+    q: libc::c_int,
+) -> libc::c_int {
+    unsafe {
+        zsh_fn_thunk(builtin_name, args, opts, q, |args| {
+            prompt::show(Prompt::Rps1, args)
+        })
+    }
+}
+
+unsafe extern "C" fn rust_prompt_ps2(
+    builtin_name: *mut libc::c_char,
+    args: *mut *mut libc::c_char,
+    opts: *mut zsh::Options,
+    q: libc::c_int,
+) -> libc::c_int {
+    unsafe {
+        zsh_fn_thunk(builtin_name, args, opts, q, |args| {
+            prompt::show(Prompt::Ps2, args)
+        })
+    }
+}
+
+unsafe extern "C" fn rust_prompt_ps4(
+    builtin_name: *mut libc::c_char,
+    args: *mut *mut libc::c_char,
+    opts: *mut zsh::Options,
+    q: libc::c_int,
+) -> libc::c_int {
+    unsafe {
+        zsh_fn_thunk(builtin_name, args, opts, q, |args| {
+            prompt::show(Prompt::Ps4, args)
+        })
+    }
+}
+
+unsafe extern "C" fn rust_prompt_sprompt(
+    builtin_name: *mut libc::c_char,
+    args: *mut *mut libc::c_char,
+    opts: *mut zsh::Options,
+    q: libc::c_int,
+) -> libc::c_int {
+    unsafe {
+        zsh_fn_thunk(builtin_name, args, opts, q, |args| {
+            prompt::show(Prompt::Sprompt, args)
+        })
+    }
 }
 
 unsafe extern "C" fn rust_prompt_pre_exec(
@@ -170,8 +342,8 @@ unsafe extern "C" fn rust_prompt_pre_exec(
     q: libc::c_int,
 ) -> libc::c_int {
     unsafe {
-        zsh_fn_thunk(builtin_name, args, opts, q, |_| {
-            prompt::pre_exec();
+        zsh_fn_thunk(builtin_name, args, opts, q, |args| {
+            prompt::pre_exec(args);
             Ok(())
         })
     }
@@ -200,12 +372,30 @@ unsafe extern "C" fn rust_prompt_set_timing_threshold(
     unsafe { zsh_fn_thunk(builtin_name, args, opts, q, prompt::set_timing_threshold) }
 }
 
-unsafe fn args_array_to_vec<'a>(args: &'a *mut *mut libc::c_char) -> Vec<&'a CStr> {
+unsafe extern "C" fn rust_prompt_async_done(
+    builtin_name: *mut libc::c_char,
+    args: *mut *mut libc::c_char,
+    opts: *mut zsh::Options,
+    q: libc::c_int,
+) -> libc::c_int {
+    unsafe { zsh_fn_thunk(builtin_name, args, opts, q, prompt::async_done) }
+}
+
+unsafe extern "C" fn rust_prompt_set(
+    builtin_name: *mut libc::c_char,
+    args: *mut *mut libc::c_char,
+    opts: *mut zsh::Options,
+    q: libc::c_int,
+) -> libc::c_int {
+    unsafe { zsh_fn_thunk(builtin_name, args, opts, q, config::set) }
+}
+
+unsafe fn args_array_to_vec(args: &*mut *mut libc::c_char) -> Vec<&CStr> {
     let mut args_vec = Vec::new();
     let mut args_iter = *args;
     loop {
         let arg = *args_iter;
-        if arg == null_mut() {
+        if arg.is_null() {
             break;
         }
         let arg = unsafe { CStr::from_ptr(arg) };
@@ -245,11 +435,13 @@ fn enables_(m: *mut zsh::Module, enables: *mut *mut libc::c_int) -> libc::c_int
 
 #[unsafe(no_mangle)]
 fn boot_(_m: *mut zsh::Module) -> libc::c_int {
+    worker::define_handler();
     0
 }
 
 #[unsafe(no_mangle)]
 fn cleanup_(m: *mut zsh::Module) -> libc::c_int {
+    prompt::shutdown();
     let mut mod_lock = MODULE_DATA.lock().unwrap();
     let module = mod_lock.deref_mut().as_mut().unwrap();
     let module_features = unsafe { module.features() };
@@ -258,6 +450,7 @@ fn cleanup_(m: *mut zsh::Module) -> libc::c_int {
 
 #[unsafe(no_mangle)]
 fn finish_(_m: *mut zsh::Module) -> libc::c_int {
+    prompt::shutdown();
     let mut mod_lock = MODULE_DATA.lock().unwrap();
     *mod_lock = None;
     0
@@ -0,0 +1,368 @@
+//! Where we are: which repository, what's checked out, and where in the working copy.
+
+use std::fmt;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::escape::{self, Untrusted};
+use crate::osc::{self, Link};
+use crate::path;
+use crate::process::TimedOut;
+use crate::theme::{Paint, Role};
+use crate::vcs::{Head, Kind, Lock, Operation, StateDir, Unsquashed};
+
+/// Where the shell is.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Cwd {
+    pub physical: PathBuf,
+    /// `$PWD`: the way the shell got here, through whichever symlinks it `cd`'d through.
+    pub logical: Option<PathBuf>,
+}
+
+impl Cwd {
+    /// Reads `$PWD`, so this must be called on the shell's own thread.
+    pub fn current() -> Option<Cwd> {
+        let physical = std::env::current_dir().ok()?;
+        let logical = unsafe { crate::zsh::get_string_param(c"PWD") }
+            .and_then(|s| s.to_str().ok())
+            .map(PathBuf::from)
+            .filter(|p| p.is_absolute());
+        Some(Cwd { physical, logical })
+    }
+}
+
+/// What's still known about a repository from the last time we looked, when its watcher has seen
+/// nothing change since.
+#[derive(Clone)]
+pub struct Unchanged {
+    pub root: PathBuf,
+    pub dirty: Option<bool>,
+    pub unsquashed: Option<Unsquashed>,
+}
+
+/// Gets the location from the daemon, if there is one; otherwise, works it out ourselves.
+pub fn locate(cwd: &Cwd, deadline: Instant, unchanged: Option<&Unchanged>) -> Location {
+    let mut location = match crate::daemon::query(&cwd.physical, deadline) {
+        Some(location) => location,
+        None => get_location(&cwd.physical, deadline, unchanged),
+    };
+    if let (Location::Repo(repo), Some(logical)) = (&mut location, &cwd.logical) {
+        if let Some(prefix) = logical_prefix(logical, &cwd.physical, &repo.root) {
+            repo.prefix = Ok(prefix);
+        }
+    }
+    location
+}
+
+/// Where `logical` is relative to `root`, going by the path the shell took to get there, so that
+/// `..` in the prompt is the `..` that `cd` would go to. `None` if `logical` isn't really
+/// `physical` (it's out of date), or if it never passes through the repository's root on its way
+/// there (a symlink from outside of the repository to somewhere inside of it), in which case
+/// only the physical prefix makes any sense.
+fn logical_prefix(logical: &Path, physical: &Path, root: &Path) -> Option<PathBuf> {
+    if logical == physical {
+        return None;
+    }
+    if fs::canonicalize(logical).ok()? != physical {
+        return None;
+    }
+    logical
+        .ancestors()
+        .find(|dir| *dir == root || fs::canonicalize(dir).is_ok_and(|d| d == root))
+        .and_then(|dir| logical.strip_prefix(dir).ok())
+        .map(Path::to_owned)
+}
+
+pub fn get_location(cwd: &Path, deadline: Instant, unchanged: Option<&Unchanged>) -> Location {
+    let (backend, root) = match crate::vcs::discover(cwd, deadline) {
+        Ok(Some(found)) => found,
+        Ok(None) => return Location::NotRepo,
+        Err(TimedOut) => return Location::TimedOut,
+    };
+
+    let prefix = match cwd.strip_prefix(&root) {
+        Ok(p) => Ok(p.to_owned()),
+        Err(_) => Err(anyhow::anyhow!(
+            "{} isn't under {}",
+            cwd.display(),
+            root.display()
+        )),
+    };
+    let ignored = match &prefix {
+        Ok(prefix) => backend.ignored(&root, prefix, deadline),
+        Err(_) => Ok(false),
+    };
+    // `git status` and `git log` are the slowest things we run; if nothing's changed, what they
+    // said last time holds.
+    let (dirty, unsquashed) = match unchanged {
+        Some(unchanged) if unchanged.root == root => {
+            (Ok(unchanged.dirty), Ok(unchanged.unsquashed))
+        }
+        _ => (
+            backend.dirty(&root, deadline),
+            backend.unsquashed(&root, deadline),
+        ),
+    };
+
+    Location::Repo(Box::new(Repo {
+        kind: backend.kind(),
+        state_dirs: backend.state_dirs(&root),
+        head: backend.head(&root, deadline),
+        dirty,
+        unsquashed,
+        operation: backend.operation(&root),
+        locks: backend.locks(&root),
+        name: crate::names::basename(&root),
+        remote: backend.remote(&root),
+        root,
+        prefix,
+        ignored,
+    }))
+}
+
+pub struct Repo {
+    pub kind: Kind,
+    pub root: PathBuf,
+    /// Where the repository keeps its own state; see `Backend::state_dirs`.
+    pub state_dirs: Vec<StateDir>,
+    /// What to call the repository; see `names`.
+    pub name: String,
+    pub remote: Option<String>,
+    pub head: anyhow::Result<Head>,
+    pub dirty: anyhow::Result<Option<bool>>,
+    /// See `Backend::unsquashed`.
+    pub unsquashed: anyhow::Result<Option<Unsquashed>>,
+    pub operation: anyhow::Result<Option<Operation>>,
+    pub locks: anyhow::Result<Vec<Lock>>,
+    /// Where we are, relative to `root`.
+    pub prefix: anyhow::Result<PathBuf>,
+    /// Whether the repository ignores where we are, so nothing made here will be tracked.
+    pub ignored: anyhow::Result<bool>,
+}
+
+impl Repo {
+    /// Whether we ran out of time on what the location can't do without. Whether the working copy
+    /// is dirty, and how many fixups there are, aren't that: without them, there's a `?` (or
+    /// nothing), and the rest is shown as usual.
+    fn timed_out(&self) -> bool {
+        fn is_timeout<T>(r: &anyhow::Result<T>) -> bool {
+            r.as_ref().is_err_and(|err| err.is::<TimedOut>())
+        }
+        is_timeout(&self.head) || is_timeout(&self.prefix) || is_timeout(&self.ignored)
+    }
+
+    /// What can be carried over to the next look at this repository, if nothing changes before then.
+    pub fn unchanged(&self) -> Option<Unchanged> {
+        Some(Unchanged {
+            root: self.root.clone(),
+            dirty: *self.dirty.as_ref().ok()?,
+            unsquashed: *self.unsquashed.as_ref().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for Repo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let remote_links = crate::config::CONFIG.lock().unwrap().remote_links;
+        let web_url = match &self.remote {
+            Some(remote) if remote_links => osc::web_url(remote),
+            _ => None,
+        };
+        write!(
+            f,
+            "{} %B{}%b:",
+            Paint(Role::Repo, self.kind.glyph()),
+            Link(web_url, Untrusted(&self.name))
+        )?;
+        match &self.head {
+            Ok(Head::Branch(b)) => {
+                write!(f, "{}", Paint(Role::BranchOk, Untrusted(escape::decode(b))))?
+            }
+            Ok(Head::Detached(s)) => write!(
+                f,
+                "{}",
+                Paint(
+                    Role::BranchDetached,
+                    format_args!("(detached HEAD: {})", Untrusted(escape::decode(s)))
+                )
+            )?,
+            Ok(Head::Hg {
+                branch,
+                bookmark,
+                revision,
+            }) => {
+                let branch = Untrusted(escape::decode(branch));
+                write!(f, "{}", Paint(Role::BranchOk, branch))?;
+                if let Some(bookmark) = bookmark {
+                    let bookmark = Untrusted(escape::decode(bookmark));
+                    write!(f, "{}", Paint(Role::Bookmark, format_args!("[{bookmark}]")))?;
+                }
+                write!(f, "@{}", Paint(Role::Revision, Untrusted(revision)))?;
+            }
+            Ok(Head::Jj {
+                change,
+                bookmarks,
+                parent_bookmarks,
+                conflict,
+            }) => {
+                write!(f, "{}", Paint(Role::Change, Untrusted(change)))?;
+                if !bookmarks.is_empty() {
+                    let bookmarks = Untrusted(bookmarks.join(" "));
+                    write!(f, " {}", Paint(Role::BranchOk, bookmarks))?;
+                }
+                if !parent_bookmarks.is_empty() {
+                    write!(
+                        f,
+                        " {}@-",
+                        Paint(Role::BranchOk, Untrusted(parent_bookmarks.join(" ")))
+                    )?;
+                }
+                if *conflict {
+                    write!(f, " %B{}%b", Paint(Role::Error, "(conflict)"))?;
+                }
+            }
+            Err(_err) => write!(f, "%B{}%b", Paint(Role::Error, "(err)"))?,
+        }
+        match &self.dirty {
+            Ok(Some(true)) => write!(f, "{}", Paint(Role::Dirty, "*"))?,
+            Ok(Some(false) | None) => (),
+            Err(_err) => write!(f, "{}", Paint(Role::Warning, "?"))?,
+        }
+        match &self.operation {
+            Ok(Some(op)) => write!(f, "{}", Paint(Role::Warning, format_args!("|{op}")))?,
+            Ok(None) => (),
+            Err(_err) => write!(f, "%B{}%b", Paint(Role::Error, "|(err)"))?,
+        }
+        match &self.unsquashed {
+            Ok(Some(Unsquashed { count: 0, .. }) | None) => (),
+            Ok(Some(n)) => write!(
+                f,
+                " {}",
+                Paint(Role::Warning, format_args!("(fixups: {n})"))
+            )?,
+            // Not worth shouting about; the other parts will have their own errors to show.
+            Err(_err) => (),
+        }
+        write!(f, ":")?;
+        match &self.prefix {
+            Ok(p) => {
+                let path = path::in_repo(&self.root, p);
+                let url = osc::file_url(self.root.join(p).as_os_str().as_bytes());
+                write!(f, "%B{}%b", Link(Some(url), Paint(Role::Path, path)))?;
+            }
+            Err(err) if err.is::<TimedOut>() => {
+                write!(f, "{}", Paint(Role::Warning, "(timed out)"))?
+            }
+            Err(_) => write!(f, "%B{}%b", Paint(Role::Error, "(err)"))?,
+        }
+        if let Ok(true) = self.ignored {
+            write!(f, " {}", Paint(Role::Ignored, "ignored"))?;
+        }
+        Ok(())
+    }
+}
+
+/// A warning about the oldest of the repository's lock files that have been around for long enough
+/// that whatever took them has most likely died; nothing, if there are none.
+pub struct StaleLocks<'a>(pub &'a Repo);
+
+impl fmt::Display for StaleLocks<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Ok(locks) = &self.0.locks else {
+            return Ok(());
+        };
+        let stale_after = {
+            let config = crate::config::CONFIG.lock().unwrap();
+            Duration::from_secs(config.stale_lock_secs.into())
+        };
+        let now = SystemTime::now();
+        let mut stale: Vec<_> = locks
+            .iter()
+            .filter_map(|lock| Some((lock, now.duration_since(lock.since).ok()?)))
+            .filter(|(_, age)| stale_after <= *age)
+            .collect();
+        stale.sort_by_key(|(_, age)| std::cmp::Reverse(*age));
+        let Some((oldest, age)) = stale.first() else {
+            return Ok(());
+        };
+        let more = match stale.len() - 1 {
+            0 => String::new(),
+            n => format!(", +{n} more"),
+        };
+        let warning = format_args!(
+            "(stale {}: {}{more})",
+            Untrusted(escape::decode(oldest.path.as_os_str().as_bytes())),
+            RoughAge(*age),
+        );
+        write!(f, "%B{}%b", Paint(Role::Error, warning))
+    }
+}
+
+/// An age to the nearest unit, for things that have been around for a while.
+struct RoughAge(Duration);
+
+impl fmt::Display for RoughAge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MINUTE: u64 = 60;
+        const HOUR: u64 = 60 * MINUTE;
+        const DAY: u64 = 24 * HOUR;
+
+        let secs = self.0.as_secs();
+        if DAY <= secs {
+            write!(f, "{}d", secs / DAY)
+        } else if HOUR <= secs {
+            write!(f, "{}h", secs / HOUR)
+        } else if MINUTE <= secs {
+            write!(f, "{}m", secs / MINUTE)
+        } else {
+            write!(f, "{secs}s")
+        }
+    }
+}
+
+pub enum Location {
+    Repo(Box<Repo>),
+    NotRepo,
+    /// A worker is still figuring out which of the above it is.
+    Pending,
+    /// We ran out of time figuring out which of the above it is.
+    TimedOut,
+    /// We've run out of time here too often, and have stopped trying.
+    Disabled,
+}
+
+impl Location {
+    pub fn timed_out(&self) -> bool {
+        match self {
+            Location::Repo(repo) => repo.timed_out(),
+            Location::TimedOut => true,
+            _ => false,
+        }
+    }
+}
+
+/// The current directory, for when it's not in a repository (or we don't yet know if it is).
+fn cwd() -> impl fmt::Display {
+    Link(osc::cwd_url(), Paint(Role::Path, path::cwd()))
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Repo(repo) => write!(f, "{repo}"),
+            Location::NotRepo | Location::Disabled => write!(f, "%B{}%b", cwd()),
+            Location::TimedOut => {
+                write!(f, "%B{}%b {}", cwd(), Paint(Role::Warning, "(timed out)"))
+            }
+            Location::Pending => {
+                let mut pending = crate::config::CONFIG.lock().unwrap().pending.clone();
+                if pending.is_empty() {
+                    pending = crate::glyphs::get().ellipsis.to_owned();
+                }
+                write!(f, "%B{}%b {pending}", cwd())
+            }
+        }
+    }
+}
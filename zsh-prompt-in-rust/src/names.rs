@@ -0,0 +1,83 @@
+//! What to call a repository in the prompt. By default, that's the name of its root directory;
+//! but `app` could be any of several checkouts, so a repository can be given an alias, and
+//! repositories that would otherwise share a name get as many of their parent directories as it
+//! takes to tell them apart.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::escape;
+
+/// How many repositories we remember having been in, to disambiguate names against.
+const MAX_RECENT: usize = 16;
+
+/// The roots of recently visited repositories, most recent first. Only the shell's own thread
+/// touches this.
+static RECENT: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Notes that we've been in the repository at `root`.
+pub fn visit(root: &Path) {
+    let mut recent = RECENT.lock().unwrap();
+    recent.retain(|r| r != root);
+    recent.insert(0, root.to_owned());
+    recent.truncate(MAX_RECENT);
+}
+
+/// The name to show for the repository at `root`, which was cloned from `remote`.
+pub fn name(root: &Path, remote: Option<&str>) -> String {
+    let config = crate::config::CONFIG.lock().unwrap();
+    let alias = config
+        .aliases
+        .iter()
+        .find(|(key, _)| Path::new(key) == root || Some(key.as_str()) == remote);
+    if let Some((_, alias)) = alias {
+        return alias.clone();
+    }
+    if config.disambiguate {
+        drop(config);
+        disambiguated(root, &RECENT.lock().unwrap())
+    } else {
+        basename(root)
+    }
+}
+
+pub fn basename(root: &Path) -> String {
+    match root.file_name() {
+        Some(fname) => escape::decode(fname.as_bytes()).into_owned(),
+        None => "???".to_owned(),
+    }
+}
+
+/// The fewest trailing components of `root` that no other recent repository's root ends in.
+fn disambiguated(root: &Path, recent: &[PathBuf]) -> String {
+    fn reversed(path: &Path) -> Vec<&OsStr> {
+        path.components()
+            .rev()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect()
+    }
+
+    let ours = reversed(root);
+    let others: Vec<_> = recent
+        .iter()
+        .filter(|r| *r != root && r.file_name() == root.file_name())
+        .map(|r| reversed(r))
+        .collect();
+    if ours.is_empty() || others.is_empty() {
+        return basename(root);
+    }
+    let n = (1..=ours.len())
+        .find(|&n| others.iter().all(|other| other.get(..n) != Some(&ours[..n])))
+        .unwrap_or(ours.len());
+    let mut suffix: Vec<_> = ours[..n]
+        .iter()
+        .map(|c| escape::decode(c.as_bytes()))
+        .collect();
+    suffix.reverse();
+    suffix.join("/")
+}
@@ -0,0 +1,151 @@
+//! Telling the terminal things it can't see for itself, with operating system commands.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
+
+use crate::config::CONFIG;
+
+/// What can't appear as itself in a `file:` URL's path: what the URL standard sets aside for a
+/// path, and `\`, which some terminals take as a separator. Bytes outside ASCII are always encoded.
+const PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'\\')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// OSC 7: tells the terminal (or tmux) which directory the shell is in, so that a new window can
+/// start there too.
+pub fn report_cwd() {
+    if !CONFIG.lock().unwrap().report_cwd {
+        return;
+    }
+    if let Some(url) = cwd_url() {
+        send(&format!("\x1b]7;{url}\x1b\\"));
+    }
+}
+
+/// `$PWD`, as a `file:` URL.
+pub fn cwd_url() -> Option<String> {
+    Some(file_url(&crate::zsh::param_bytes(c"PWD")?))
+}
+
+/// `path`, on this host, as a `file:` URL.
+pub fn file_url(path: &[u8]) -> String {
+    // The host as `%M` has it, which is `$HOST`.
+    let host = crate::zsh::param_bytes(c"HOST").unwrap_or_default();
+    format!(
+        "file://{}{}",
+        percent_encode(&host, PATH),
+        percent_encode(path, PATH)
+    )
+}
+
+/// Where to see a repository on the web, going by a remote's URL: `git@github.com:me/repo.git`,
+/// `ssh://git@host:22/me/repo` and `https://host/me/repo.git` all become `https://host/me/repo`.
+/// An `http:` or `https:` remote is already on the web server, so it keeps its scheme and port:
+/// `http://host:8080/me/repo`. `None` for remotes that aren't on a web server at all, like a local
+/// path.
+pub fn web_url(remote: &str) -> Option<String> {
+    let (scheme, host, path) = match remote.split_once("://") {
+        Some((scheme @ ("https" | "http"), rest)) => {
+            let (host, path) = rest.split_once('/')?;
+            (scheme, host, path)
+        }
+        Some(("ssh" | "git" | "git+ssh", rest)) => {
+            let (host, path) = rest.split_once('/')?;
+            // The port is SSH's (or git's), rather than the web server's.
+            let port = host
+                .rsplit_once(':')
+                .filter(|(_, port)| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()));
+            ("https", port.map_or(host, |(h, _)| h), path)
+        }
+        Some(_) => return None,
+        // scp-like: `[user@]host:path`, with no port.
+        None => {
+            let (host, path) = remote.split_once(':')?;
+            if host.contains('/') {
+                return None;
+            }
+            ("https", host, path)
+        }
+    };
+    let host = host.rsplit_once('@').map_or(host, |(_, h)| h);
+    let path = path.trim_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    if host.is_empty() || path.is_empty() {
+        return None;
+    }
+    let url = format!("{scheme}://{host}/{path}");
+    // It came out of a config file, and it's going to the terminal.
+    if url.chars().any(|c| c.is_control() || c.is_whitespace()) {
+        return None;
+    }
+    Some(url)
+}
+
+/// OSC 8: shows `.1`, linked to `.0`, if there's a URL to link to and links are on. The link
+/// goes inside `%{ %}`, as it takes up no room on the screen.
+pub struct Link<T>(pub Option<String>, pub T);
+
+impl<T: fmt::Display> fmt::Display for Link<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(url) if CONFIG.lock().unwrap().links => write!(
+                f,
+                "%{{\x1b]8;;{}\x1b\\%}}{}%{{\x1b]8;;\x1b\\%}}",
+                url.replace('%', "%%"),
+                self.1
+            ),
+            _ => write!(f, "{}", self.1),
+        }
+    }
+}
+
+/// OSC 133, FinalTerm's semantic prompt marks, which tell a terminal where each prompt, command
+/// line and command's output starts. The prompt's own go in the prompt, where zsh mustn't count
+/// them; see `prompt_marks`.
+pub fn marks() -> bool {
+    CONFIG.lock().unwrap().marks
+}
+
+/// `A`, for the start of the prompt, and `B`, for the end of it and the start of the command line.
+pub fn prompt_marks() -> (&'static str, &'static str) {
+    if marks() {
+        ("%{\x1b]133;A\x1b\\%}", "%{\x1b]133;B\x1b\\%}")
+    } else {
+        ("", "")
+    }
+}
+
+/// `C`, for the end of the command line and the start of its output; from `preexec`.
+pub fn command_started() {
+    if marks() {
+        send("\x1b]133;C\x1b\\");
+    }
+}
+
+/// `D`, for the end of a command's output, and how it exited; from `precmd`.
+pub fn command_finished(status: i32) {
+    if marks() {
+        send(&format!("\x1b]133;D;{status}\x1b\\"));
+    }
+}
+
+/// Writes `sequence` out, so long as there's a terminal to read it.
+pub fn send(sequence: &str) {
+    if unsafe { libc::isatty(libc::STDOUT_FILENO) } != 1 {
+        return;
+    }
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(sequence.as_bytes());
+    let _ = stdout.flush();
+}
@@ -0,0 +1,438 @@
+//! Shows directories, shortening them when they'd otherwise take up too much of the line.
+//!
+//! A path is a head, which is always shown as is (`~`, a named directory like `~svc`, or `/`; in a
+//! repository, the `/` after its name), and the components after it. The configured strategies are
+//! tried in order, each shortening as little as it can, until the path fits in its share of
+//! `$COLUMNS`. The last component is never touched.
+
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::config::CONFIG;
+use crate::escape;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// `~/s/d/project`: components down to their first letter, as fish does.
+    Fish,
+    /// `~/src/…/project`: components from the middle, replaced with an ellipsis.
+    Ellipsis,
+    /// `…/dotfiles/project`: all but the last few components.
+    Last(usize),
+    /// `~/sr/do/project`: components down to the shortest prefix that no sibling shares.
+    Unique,
+}
+
+impl Strategy {
+    /// `fish`, `ellipsis`, `last:N`, or `unique`.
+    pub fn parse(s: &str) -> Option<Strategy> {
+        match s {
+            "fish" => Some(Strategy::Fish),
+            "ellipsis" => Some(Strategy::Ellipsis),
+            "unique" => Some(Strategy::Unique),
+            _ => {
+                let n = s.strip_prefix("last:")?.parse().ok()?;
+                Some(Strategy::Last(n)).filter(|_| 0 < n)
+            }
+        }
+    }
+}
+
+/// How long `Strategy::Unique` may spend listing directories, for one path; after that, it's down
+/// to first letters, as with `Strategy::Fish`. On a slow filesystem, it isn't worth the wait.
+const UNIQUE_BUDGET: Duration = Duration::from_millis(20);
+
+/// Directories with more in them than this aren't listed for `Strategy::Unique`.
+const MAX_SIBLINGS: usize = 4096;
+
+/// Set while the prompt is being squeezed into a narrow terminal; see `squeezed`.
+static SQUEEZED: AtomicBool = AtomicBool::new(false);
+
+/// Runs `f` with directories shortened as far as they'll go, whatever their share of the line.
+pub fn squeezed<R>(f: impl FnOnce() -> R) -> R {
+    SQUEEZED.store(true, Ordering::Relaxed);
+    let result = f();
+    SQUEEZED.store(false, Ordering::Relaxed);
+    result
+}
+
+struct Component {
+    name: String,
+    shown: String,
+    /// The directory this one is in, for `Strategy::Unique`; `None` for a component that was
+    /// never a directory's name (an ellipsis).
+    parent: Option<PathBuf>,
+}
+
+impl Component {
+    fn is_ellipsis(&self) -> bool {
+        self.parent.is_none()
+    }
+}
+
+/// A directory to show: `head`, then `components`, which are the directories under `base`.
+pub struct Shortened {
+    head: String,
+    components: Vec<Component>,
+    /// What stands in for the components left out.
+    ellipsis: &'static str,
+}
+
+impl Shortened {
+    pub fn new(head: String, base: &Path, relative: &Path) -> Shortened {
+        let mut path = Shortened::whole(head, base, relative, crate::glyphs::get().ellipsis);
+        let (strategies, percent) = {
+            let config = CONFIG.lock().unwrap();
+            (config.shorten().to_vec(), config.path_width_percent)
+        };
+        let budget = if SQUEEZED.load(Ordering::Relaxed) {
+            0
+        } else {
+            crate::width::columns() * percent as usize / 100
+        };
+        path.shorten(&strategies, budget);
+        path
+    }
+
+    /// The path, not yet shortened.
+    fn whole(head: String, base: &Path, relative: &Path, ellipsis: &'static str) -> Shortened {
+        let mut parent = base.to_owned();
+        let mut components = Vec::new();
+        for name in relative.iter() {
+            components.push(Component {
+                shown: escape::decode(name.as_bytes()).into_owned(),
+                name: name.to_string_lossy().into_owned(),
+                parent: Some(parent.clone()),
+            });
+            parent.push(name);
+        }
+        Shortened {
+            head,
+            components,
+            ellipsis,
+        }
+    }
+
+    fn width(&self) -> usize {
+        let separators = match self.head.as_str() {
+            // `/` is its own separator.
+            "/" => self.components.len().saturating_sub(1),
+            _ => self.components.len(),
+        };
+        self.head.chars().count()
+            + separators
+            + self
+                .components
+                .iter()
+                .map(|c| c.shown.chars().count())
+                .sum::<usize>()
+    }
+
+    fn ellipsis(&self) -> Component {
+        Component {
+            name: self.ellipsis.to_owned(),
+            shown: self.ellipsis.to_owned(),
+            parent: None,
+        }
+    }
+
+    /// Shortens this, trying each of `strategies` in turn, until it's no wider than `budget`.
+    fn shorten(&mut self, strategies: &[Strategy], budget: usize) {
+        let deadline = Instant::now() + UNIQUE_BUDGET;
+        for &strategy in strategies {
+            if self.width() <= budget {
+                return;
+            }
+            // Everything but the last component is fair game.
+            let middle = self.components.len().saturating_sub(1);
+            match strategy {
+                Strategy::Fish | Strategy::Unique => {
+                    for i in 0..middle {
+                        if self.width() <= budget {
+                            break;
+                        }
+                        let c = &mut self.components[i];
+                        let unique = match (strategy, &c.parent) {
+                            (Strategy::Unique, Some(parent)) => {
+                                unique_prefix(parent, &c.name, deadline)
+                            }
+                            _ => None,
+                        };
+                        c.shown = unique.unwrap_or_else(|| first_letter(&c.shown));
+                    }
+                }
+                Strategy::Ellipsis => {
+                    // Keep the first component for as long as we can: it says where we are,
+                    // broadly, as the last says where we are exactly.
+                    let mut start = match self.components.first() {
+                        Some(c) if c.is_ellipsis() => 0,
+                        _ => 1,
+                    };
+                    while budget < self.width() {
+                        let last = self.components.len().saturating_sub(1);
+                        if last <= start {
+                            if start == 0 {
+                                break;
+                            }
+                            start = 0;
+                        } else if !self.components[start].is_ellipsis() {
+                            self.components[start] = self.ellipsis();
+                        } else if start + 1 < last {
+                            self.components.remove(start + 1);
+                        } else if start == 1 {
+                            self.components.remove(0);
+                            start = 0;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                Strategy::Last(n) => {
+                    if n < self.components.len() {
+                        let start = self.components.len() - n;
+                        self.components.drain(..start);
+                        if !self.components[0].is_ellipsis() {
+                            self.components.insert(0, self.ellipsis());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// `.config` becomes `.c`, rather than just `.`.
+fn first_letter(name: &str) -> String {
+    let dots = name.chars().take_while(|&c| c == '.').count();
+    name.chars().take(dots + 1).collect()
+}
+
+/// The shortest prefix of `name` that no other directory in `parent` starts with; `None` if
+/// `parent` has too much in it to list by `deadline`.
+fn unique_prefix(parent: &Path, name: &str, deadline: Instant) -> Option<String> {
+    if deadline <= Instant::now() {
+        return None;
+    }
+    let Ok(entries) = fs::read_dir(parent) else {
+        return Some(name.to_owned());
+    };
+    let mut siblings = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        if MAX_SIBLINGS <= siblings.len() || deadline <= Instant::now() {
+            return None;
+        }
+        let sibling = entry.file_name().to_string_lossy().into_owned();
+        if sibling != name && entry.file_type().is_ok_and(|t| t.is_dir()) {
+            siblings.push(sibling);
+        }
+    }
+    let mut prefix = String::new();
+    for c in name.chars() {
+        prefix.push(c);
+        if !siblings.iter().any(|s| s.starts_with(&prefix)) {
+            return Some(prefix);
+        }
+    }
+    Some(name.to_owned())
+}
+
+impl Shortened {
+    /// As text, rather than for a prompt: with `%` as it is, and control characters left in.
+    pub fn plain(&self) -> String {
+        let mut out = String::new();
+        let _ = self.write(&mut out, |s| Cow::Borrowed(s));
+        out
+    }
+
+    fn write(&self, out: &mut impl fmt::Write, escape: fn(&str) -> Cow<'_, str>) -> fmt::Result {
+        out.write_str(&escape(&self.head))?;
+        for (i, c) in self.components.iter().enumerate() {
+            if i != 0 || self.head != "/" {
+                out.write_str("/")?;
+            }
+            out.write_str(&escape(&c.shown))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Shortened {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, escape::prompt)
+    }
+}
+
+/// The current directory, as `%~` would show it: under `~`, or whichever named directory takes the
+/// most off it, as zsh's own `finddir` picks.
+pub fn cwd() -> Shortened {
+    let (head, base, relative) = cwd_parts();
+    Shortened::new(head, &base, &relative)
+}
+
+/// The current directory as `cwd` has it, but whole, however long it is.
+pub fn cwd_whole() -> Shortened {
+    let (head, base, relative) = cwd_parts();
+    Shortened::whole(head, &base, &relative, crate::glyphs::get().ellipsis)
+}
+
+/// The current directory, as a head, the directory it stands for, and the rest.
+fn cwd_parts() -> (String, PathBuf, PathBuf) {
+    let param = |name| crate::zsh::param_bytes(name).map(|b| PathBuf::from(OsString::from_vec(b)));
+    let pwd = param(c"PWD")
+        .or_else(|| std::env::current_dir().ok())
+        .unwrap_or_default();
+
+    let mut best = None;
+    if let Some(home) = param(c"HOME").filter(|h| h.as_os_str().len() > 1) {
+        if pwd.starts_with(&home) {
+            let saving = home.as_os_str().len();
+            best = Some(("~".to_owned(), home, saving));
+        }
+    }
+    for (name, dir) in unsafe { crate::zsh::named_dirs() } {
+        let saving = dir.as_os_str().len().saturating_sub(name.len());
+        // As in `finddir`, a name has to be shorter than its directory to be worth using.
+        if 0 < saving && pwd.starts_with(&dir) && best.as_ref().is_none_or(|(_, _, s)| *s < saving)
+        {
+            best = Some((format!("~{}", escape::decode(name.as_bytes())), dir, saving));
+        }
+    }
+    match best {
+        Some((head, dir, _)) => {
+            let relative = pwd.strip_prefix(&dir).unwrap().to_owned();
+            (head, dir, relative)
+        }
+        None => {
+            let relative = pwd.strip_prefix("/").unwrap_or(&pwd).to_owned();
+            ("/".to_owned(), PathBuf::from("/"), relative)
+        }
+    }
+}
+
+/// `prefix`, in the repository at `root`: from the root, or from whichever named directory inside
+/// the repository takes the most off it, e.g. `~svc/handlers`.
+pub fn in_repo(root: &Path, prefix: &Path) -> Shortened {
+    let mut best: Option<(OsString, PathBuf)> = None;
+    for (name, dir) in unsafe { crate::zsh::named_dirs() } {
+        // Only as the directory was given: resolving each of them on every prompt could be slow.
+        let Ok(inside) = dir.strip_prefix(root) else {
+            continue;
+        };
+        let saving = |inside: &Path, name: &OsString| {
+            inside.as_os_str().len() as isize - name.len() as isize
+        };
+        // Only names shorter than what they stand for, as in `finddir`; that rules out the root.
+        if 0 < saving(inside, &name)
+            && prefix.starts_with(inside)
+            && best
+                .as_ref()
+                .is_none_or(|(n, i)| saving(i, n) < saving(inside, &name))
+        {
+            best = Some((name, inside.to_owned()));
+        }
+    }
+    match best {
+        Some((name, inside)) => Shortened::new(
+            format!("~{}", escape::decode(name.as_bytes())),
+            &root.join(&inside),
+            prefix.strip_prefix(&inside).unwrap(),
+        ),
+        None => Shortened::new("/".to_owned(), root, prefix),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shorten(head: &str, relative: &str, strategies: &[Strategy], budget: usize) -> String {
+        let mut path = Shortened::whole(head.to_owned(), Path::new("/"), Path::new(relative), "…");
+        path.shorten(strategies, budget);
+        path.to_string()
+    }
+
+    #[test]
+    fn fits_already() {
+        let all = [Strategy::Fish, Strategy::Ellipsis, Strategy::Last(1)];
+        assert_eq!(shorten("~", "src/dotfiles", &all, 14), "~/src/dotfiles");
+        assert_eq!(shorten("/", "usr/share/doc", &all, 14), "/usr/share/doc");
+        assert_eq!(shorten("~", "", &all, 0), "~");
+    }
+
+    #[test]
+    fn fish() {
+        let fish = [Strategy::Fish];
+        assert_eq!(
+            shorten("~", "src/dotfiles/project", &fish, 0),
+            "~/s/d/project"
+        );
+        // Only as far as it has to, from the left.
+        assert_eq!(
+            shorten("~", "src/dotfiles/project", &fish, 20),
+            "~/s/dotfiles/project"
+        );
+        assert_eq!(shorten("/", "usr/share/doc", &fish, 0), "/u/s/doc");
+        assert_eq!(shorten("~", ".config/nvim/lua", &fish, 0), "~/.c/n/lua");
+        // The last component is never touched.
+        assert_eq!(shorten("~", "a-long-name", &fish, 0), "~/a-long-name");
+    }
+
+    #[test]
+    fn ellipsis() {
+        let ellipsis = [Strategy::Ellipsis];
+        // The first component goes last of all.
+        assert_eq!(
+            shorten("~", "src/a/b/c/project", &ellipsis, 15),
+            "~/src/…/project"
+        );
+        assert_eq!(
+            shorten("~", "src/a/b/c/project", &ellipsis, 17),
+            "~/src/…/c/project"
+        );
+        assert_eq!(
+            shorten("~", "src/a/b/c/project", &ellipsis, 0),
+            "~/…/project"
+        );
+        assert_eq!(shorten("/", "usr/share/doc/zsh", &ellipsis, 0), "/…/zsh");
+        assert_eq!(shorten("~", "src/project", &ellipsis, 0), "~/…/project");
+    }
+
+    #[test]
+    fn last() {
+        assert_eq!(
+            shorten("~", "src/a/b/project", &[Strategy::Last(2)], 0),
+            "~/…/b/project"
+        );
+        assert_eq!(
+            shorten("~", "src/a/b/project", &[Strategy::Last(1)], 0),
+            "~/…/project"
+        );
+        // Nothing to leave out.
+        assert_eq!(
+            shorten("~", "b/project", &[Strategy::Last(2)], 0),
+            "~/b/project"
+        );
+    }
+
+    #[test]
+    fn in_turn() {
+        let both = [Strategy::Fish, Strategy::Ellipsis];
+        assert_eq!(
+            shorten("~", "src/a/b/c/project", &both, 17),
+            "~/s/a/b/c/project"
+        );
+        assert_eq!(
+            shorten("~", "src/a/b/c/project", &both, 13),
+            "~/s/…/project"
+        );
+        // An ellipsis Last left is reused, rather than doubled.
+        let both = [Strategy::Last(3), Strategy::Ellipsis];
+        assert_eq!(shorten("~", "src/a/b/c/project", &both, 0), "~/…/project");
+    }
+}
@@ -0,0 +1,69 @@
+//! Running children without letting them hold up the prompt.
+
+use std::fmt;
+use std::io::Read;
+use std::os::fd::AsFd;
+use std::process::{Child, Command, Output, Stdio};
+use std::time::{Duration, Instant};
+
+use nix::poll::{PollFd, PollFlags, PollTimeout};
+
+/// A child (or, more generally, some work) didn't finish before its deadline.
+#[derive(Debug)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timed out")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Runs `cmd`, collecting its stdout, but gives up (killing and reaping the child) at
+/// `deadline`. A hung filesystem would otherwise have us blocked in `wait()` forever.
+pub fn output_until(cmd: &mut Command, deadline: Instant) -> anyhow::Result<Output> {
+    let mut child = cmd.stdin(Stdio::null()).stdout(Stdio::piped()).spawn()?;
+    let result = read_until(&mut child, deadline);
+    if result.is_err() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    result
+}
+
+fn read_until(child: &mut Child, deadline: Instant) -> anyhow::Result<Output> {
+    let mut stdout_pipe = child.stdout.take().unwrap();
+    let mut stdout = Vec::new();
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let timeout = PollTimeout::try_from(remaining).unwrap_or(PollTimeout::MAX);
+        let mut fds = [PollFd::new(stdout_pipe.as_fd(), PollFlags::POLLIN)];
+        match nix::poll::poll(&mut fds, timeout) {
+            Ok(0) => return Err(TimedOut.into()),
+            Ok(_) => (),
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+        let mut buf = [0u8; 4096];
+        match stdout_pipe.read(&mut buf)? {
+            0 => break,
+            n => stdout.extend_from_slice(&buf[..n]),
+        }
+    }
+    // stdout is closed, so the child is on its way out; but a child can close stdout and still
+    // hang, so keep to the deadline.
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Output {
+                status,
+                stdout,
+                stderr: Vec::new(),
+            });
+        }
+        if deadline <= Instant::now() {
+            return Err(TimedOut.into());
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
@@ -1,18 +1,28 @@
-use std::ffi::{CStr, OsString};
-use std::fmt;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt::{self, Write as _};
 use std::io::{self, Write};
-use std::os::unix::ffi::OsStringExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
 use chrono::{DateTime, Local};
 use nix::sys::signal::Signal;
 
+use crate::escape::Untrusted;
+use crate::location::{locate, Cwd, Location, StaleLocks, Unchanged};
+use crate::template::{Segment, Template};
+use crate::theme::{Paint, Palette, Role};
+use crate::worker::Worker;
+
 struct TimingInfo {
     last_start: Option<Instant>,
     last_duration: Option<Duration>,
     last_end_dt: Option<DateTime<Local>>,
+    /// `$?`, as of `pre_cmd`. By the time a `zle reset-prompt` re-renders the prompt, `$?` is
+    /// that of whatever widget ran last.
+    last_exit_status: Option<i32>,
 }
 
 impl TimingInfo {
@@ -21,6 +31,7 @@ impl TimingInfo {
             last_start: None,
             last_duration: None,
             last_end_dt: None,
+            last_exit_status: None,
         }
     }
 }
@@ -28,10 +39,18 @@ impl TimingInfo {
 static TIMING_INFO: Mutex<TimingInfo> = Mutex::new(TimingInfo::new());
 static TIMING_THRESHOLD_MS: Mutex<u32> = Mutex::new(500);
 
-pub fn pre_exec() {
+/// `preexec`, with zsh's first argument to it: the command line, as it was typed.
+pub fn pre_exec(args: &[&CStr]) {
     let now = Instant::now();
     let mut lock = TIMING_INFO.lock().unwrap();
     lock.last_start = Some(now);
+    drop(lock);
+
+    crate::osc::command_started();
+    if let Some(command) = args.first() {
+        let command = crate::zsh::unmetafy(command);
+        crate::title::at_exec(&crate::escape::decode(&command));
+    }
 }
 
 pub fn pre_cmd() {
@@ -39,6 +58,7 @@ pub fn pre_cmd() {
     let end_dt = Local::now();
     let mut lock = TIMING_INFO.lock().unwrap();
 
+    let ran = lock.last_start.is_some();
     if let Some(start) = lock.last_start.take() {
         let duration = end - start;
         lock.last_duration = Some(duration);
@@ -47,6 +67,262 @@ pub fn pre_cmd() {
     }
     lock.last_start = None;
     lock.last_end_dt = Some(end_dt);
+    lock.last_exit_status = unsafe { crate::zsh::get_string_param(c"?") }
+        .and_then(|s| s.to_str().ok())
+        .and_then(|s| s.parse().ok());
+    let status = lock.last_exit_status;
+    drop(lock);
+
+    // Only a command that was started (so, not an empty line) has any output to end.
+    if ran {
+        crate::osc::command_finished(status.unwrap_or(0));
+    }
+
+    Palette::invalidate();
+    refresh_location();
+    update_title();
+    crate::osc::report_cwd();
+}
+
+/// The location, as of the last `pre_cmd`. This lives in the shell proper, as opposed to the
+/// `$(…)` subshell the prompt is rendered in, so that it survives from one prompt to the next.
+struct CachedLocation {
+    cwd: Cwd,
+    location: Location,
+}
+
+static LOCATION_CACHE: Mutex<Option<CachedLocation>> = Mutex::new(None);
+
+/// A worker computing the location for `cwd`, when `async` is on.
+struct InFlight {
+    cwd: Cwd,
+    worker: Worker<Location>,
+}
+
+static IN_FLIGHT: Mutex<Option<InFlight>> = Mutex::new(None);
+
+fn refresh_location() {
+    let Some(cwd) = Cwd::current() else {
+        *LOCATION_CACHE.lock().unwrap() = None;
+        *IN_FLIGHT.lock().unwrap() = None;
+        return;
+    };
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    if in_flight.as_ref().is_some_and(|f| f.cwd != cwd) {
+        // We've moved on; whatever that worker comes up with is no longer of interest.
+        *in_flight = None;
+    }
+
+    if TIMEOUTS.lock().unwrap().is_disabled(&cwd.physical) {
+        *in_flight = None;
+        drop(in_flight);
+        store_location(cwd, Location::Disabled);
+        return;
+    }
+
+    // Take the watcher's verdict before looking at the repository, so that changes made while we
+    // look get picked up next time. Only a repository is watched, so only a repository's location
+    // can be reused; and once we've moved elsewhere in it, still whether it's dirty.
+    let clean = crate::watch::take_clean();
+    let (cached, unchanged) = match LOCATION_CACHE.lock().unwrap().as_ref() {
+        Some(CachedLocation {
+            cwd: cached_cwd,
+            location: Location::Repo(repo),
+        }) => (*cached_cwd == cwd, repo.unchanged().filter(|_| clean)),
+        _ => (false, None),
+    };
+    if clean && cached {
+        return;
+    }
+
+    let (async_location, budget, async_budget) = {
+        let config = crate::config::CONFIG.lock().unwrap();
+        (
+            config.async_location,
+            Duration::from_millis(config.budget_ms.into()),
+            Duration::from_millis(config.async_budget_ms.into()),
+        )
+    };
+
+    if async_location {
+        if in_flight.is_some() {
+            return;
+        }
+        let worker = {
+            let cwd = cwd.clone();
+            let deadline = Instant::now() + async_budget;
+            let unchanged = unchanged.clone();
+            Worker::spawn(move || locate(&cwd, deadline, unchanged.as_ref()))
+        };
+        // If we can't get a thread, fall back to doing the work right here.
+        if let Ok(mut worker) = worker {
+            // Most of the time, the location is quick to get; not drawing the prompt twice is
+            // worth a short wait.
+            if let Some(location) = worker.wait(budget) {
+                drop(in_flight);
+                store_location(cwd, location);
+            } else {
+                worker.register();
+                *in_flight = Some(InFlight { cwd, worker });
+            }
+            return;
+        }
+    }
+
+    *in_flight = None;
+    drop(in_flight);
+    let location = get_location_within(&cwd, budget, unchanged);
+    store_location(cwd, location);
+}
+
+/// Gets the location, giving up once `budget` is exhausted. Not everything we do can be
+/// interrupted (reading a file on a hung NFS mount, say), so the work happens on a thread we can
+/// walk away from.
+fn get_location_within(cwd: &Cwd, budget: Duration, unchanged: Option<Unchanged>) -> Location {
+    let deadline = Instant::now() + budget;
+    let (tx, rx) = std::sync::mpsc::channel();
+    let spawned = {
+        let cwd = cwd.clone();
+        let unchanged = unchanged.clone();
+        std::thread::Builder::new()
+            .name("prompt-location".to_owned())
+            .spawn(move || {
+                let _ = tx.send(locate(&cwd, deadline, unchanged.as_ref()));
+            })
+    };
+    if spawned.is_err() {
+        return locate(cwd, deadline, unchanged.as_ref());
+    }
+    rx.recv_timeout(budget).unwrap_or(Location::TimedOut)
+}
+
+fn store_location(cwd: Cwd, mut location: Location) {
+    if location.timed_out() {
+        let key = match &location {
+            Location::Repo(repo) => repo.root.clone(),
+            _ => cwd.physical.clone(),
+        };
+        TIMEOUTS.lock().unwrap().record(key);
+    }
+    if let Location::Repo(repo) = &mut location {
+        crate::names::visit(&repo.root);
+        repo.name = crate::names::name(&repo.root, repo.remote.as_deref());
+    }
+    let target = match &location {
+        Location::Repo(repo) => Some(crate::watch::Target {
+            kind: repo.kind,
+            state_dirs: repo.state_dirs.clone(),
+            worktree: repo.root.clone(),
+        }),
+        Location::NotRepo | Location::Pending | Location::TimedOut | Location::Disabled => None,
+    };
+    crate::watch::retarget(target);
+    *LOCATION_CACHE.lock().unwrap() = Some(CachedLocation { cwd, location });
+}
+
+/// Sets the terminal's title from the location we have for where the shell is now, if any.
+fn update_title() {
+    let cwd = Cwd::current();
+    let cache = LOCATION_CACHE.lock().unwrap();
+    let location = cache
+        .as_ref()
+        .filter(|c| cwd.as_ref() == Some(&c.cwd))
+        .map(|c| &c.location);
+    crate::title::at_prompt(location);
+}
+
+/// After this many timeouts in one place, we stop trying there, for the rest of the session.
+const TIMEOUTS_BEFORE_DISABLING: u32 = 3;
+
+struct Timeouts {
+    counts: Vec<(PathBuf, u32)>,
+}
+
+impl Timeouts {
+    const fn new() -> Timeouts {
+        Timeouts { counts: Vec::new() }
+    }
+
+    fn record(&mut self, dir: PathBuf) {
+        let index = match self.counts.iter().position(|(d, _)| *d == dir) {
+            Some(i) => i,
+            None => {
+                self.counts.push((dir, 0));
+                self.counts.len() - 1
+            }
+        };
+        let (dir, count) = &mut self.counts[index];
+        *count += 1;
+        if *count == TIMEOUTS_BEFORE_DISABLING {
+            eprintln!(
+                "prompt: the location segment timed out {TIMEOUTS_BEFORE_DISABLING} times under {}; \
+                 disabling it there for this session.",
+                dir.display(),
+            );
+        }
+    }
+
+    fn is_disabled(&self, cwd: &Path) -> bool {
+        self.counts
+            .iter()
+            .any(|(d, count)| TIMEOUTS_BEFORE_DISABLING <= *count && cwd.starts_with(d))
+    }
+}
+
+static TIMEOUTS: Mutex<Timeouts> = Mutex::new(Timeouts::new());
+
+/// Called (via `zle -F`) when the worker's result is ready. Succeeds if the location was updated,
+/// and so the prompt needs to be redrawn.
+pub fn async_done(args: &[&CStr]) -> Result<(), i32> {
+    if args.len() != 1 {
+        eprintln!(
+            "Usage:\n\
+             \t_rust-prompt-alpha_async-done FD"
+        );
+        return Err(1);
+    }
+    let fd = {
+        let arg = args[0];
+        let arg = match arg.to_str() {
+            Ok(s) => s,
+            Err(_err) => {
+                eprintln!("Could not covert $1 to &str");
+                return Err(1);
+            }
+        };
+        match arg.parse::<i32>() {
+            Ok(v) => v,
+            Err(_err) => {
+                eprintln!("Could not convert $1 to integer");
+                return Err(1);
+            }
+        }
+    };
+
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    let location = match in_flight.as_ref() {
+        Some(f) if f.worker.fd() == fd => f.worker.try_take(),
+        _ => {
+            // Not a worker we know about (anymore); make sure zle stops asking.
+            crate::worker::zle_fd_handler(fd, None);
+            return Err(1);
+        }
+    };
+    let Some(location) = location else {
+        return Err(1);
+    };
+    let finished = in_flight.take().unwrap();
+    drop(in_flight);
+    store_location(finished.cwd, location);
+    update_title();
+    Ok(())
+}
+
+/// Tears down anything running in the background; called as the module is unloaded.
+pub fn shutdown() {
+    let _ = crate::watch::set_enabled(false);
+    *IN_FLIGHT.lock().unwrap() = None;
+    *LOCATION_CACHE.lock().unwrap() = None;
 }
 
 pub fn set_timing_threshold(args: &[&CStr]) -> Result<(), i32> {
@@ -78,21 +354,372 @@ pub fn set_timing_threshold(args: &[&CStr]) -> Result<(), i32> {
     Ok(())
 }
 
-pub fn prompt(args: &[&CStr]) -> Result<(), i32> {
-    let args = parse_args(args)?;
+/// The prompts zsh has, each of which has a builtin to render it from its own template.
+#[derive(Clone, Copy)]
+pub enum Prompt {
+    Ps1,
+    Rps1,
+    /// Shown while a command is continued onto more lines.
+    Ps2,
+    /// Shown before each command `set -x` traces.
+    Ps4,
+    /// The question `setopt correct` asks.
+    Sprompt,
+}
+
+impl Prompt {
+    pub const ALL: [Prompt; 5] = [
+        Prompt::Ps1,
+        Prompt::Rps1,
+        Prompt::Ps2,
+        Prompt::Ps4,
+        Prompt::Sprompt,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    fn builtin(self) -> &'static str {
+        match self {
+            Prompt::Ps1 => "_rust-prompt-alpha",
+            Prompt::Rps1 => "_rust-prompt-alpha_rprompt",
+            Prompt::Ps2 => "_rust-prompt-alpha_ps2",
+            Prompt::Ps4 => "_rust-prompt-alpha_ps4",
+            Prompt::Sprompt => "_rust-prompt-alpha_sprompt",
+        }
+    }
+
+    /// The `_rust-prompt-alpha_set` option that sets its template.
+    fn option(self) -> &'static str {
+        match self {
+            Prompt::Ps1 => "template",
+            Prompt::Rps1 => "rprompt-template",
+            Prompt::Ps2 => "ps2-template",
+            Prompt::Ps4 => "ps4-template",
+            Prompt::Sprompt => "sprompt-template",
+        }
+    }
+
+    pub fn for_option(option: &str) -> Option<Prompt> {
+        Prompt::ALL.into_iter().find(|p| p.option() == option)
+    }
+
+    pub fn default_template(self) -> &'static str {
+        match self {
+            Prompt::Ps1 => {
+                "{host}{location}{?locks: {locks}}\\n\
+                 {?timing:{timing}\\n}{?exit:{exit}\\n}{keymode}{symbol} "
+            }
+            Prompt::Rps1 => "{?status:{status} }{?timing:{timing} }{clock}",
+            Prompt::Ps2 => "{parser} {symbol} ",
+            Prompt::Ps4 => "+{source} {elapsed}> ",
+            Prompt::Sprompt => "correct {typo} to {correction}? [nyae] ",
+        }
+    }
+}
+
+/// Renders one of the prompts; each of the builtins for them takes the same arguments.
+pub fn show(which: Prompt, args: &[&CStr]) -> Result<(), i32> {
+    let args = parse_args(which.builtin(), args)?;
+    let template = crate::config::CONFIG.lock().unwrap().template(which);
+    let narrow = match which {
+        Prompt::Ps1 => crate::config::CONFIG.lock().unwrap().narrow_template(),
+        _ => None,
+    };
+    let out = match which {
+        Prompt::Ps4 => render_traced(&args, &template),
+        _ => render(&args, &template, narrow.as_ref()),
+    };
+    let (start, end) = match which {
+        Prompt::Ps1 => crate::osc::prompt_marks(),
+        _ => ("", ""),
+    };
+    print!("{start}{out}{end}");
+    io::stdout().flush().unwrap();
+    Ok(())
+}
+
+/// Renders `template`; or, given the `narrow` template to fall back on, whatever layout fits the
+/// terminal best.
+fn render(args: &Args, template: &Template, narrow: Option<&Template>) -> String {
+    let cwd = Cwd::current();
+    let cache = LOCATION_CACHE.lock().unwrap();
+    let cached = match (cache.as_ref(), &cwd) {
+        (Some(c), Some(cwd)) if c.cwd == *cwd => Some(&c.location),
+        _ => None,
+    };
+    let pending = match (IN_FLIGHT.lock().unwrap().as_ref(), &cwd) {
+        (Some(f), Some(cwd)) => f.cwd == *cwd,
+        _ => false,
+    };
+    // Only if `pre_cmd` isn't hooked up (or the directory changed under us) is there neither a
+    // cached location, nor one on the way. And if the template doesn't show it, there's no need
+    // for one at all.
+    let mut fresh;
+    let location = match (cached, &cwd) {
+        _ if !needs_location(template) && !narrow.is_some_and(needs_location) => None,
+        (Some(l), _) => Some(l),
+        (None, _) if pending => Some(&Location::Pending),
+        (None, Some(cwd)) => {
+            let budget = crate::config::CONFIG.lock().unwrap().budget_ms;
+            fresh = get_location_within(cwd, Duration::from_millis(budget.into()), None);
+            if let Location::Repo(repo) = &mut fresh {
+                repo.name = crate::names::name(&repo.root, repo.remote.as_deref());
+            }
+            Some(&fresh)
+        }
+        (None, None) => Some(&Location::NotRepo),
+    };
+    let mut ctx = context(args, location);
+    let render = |ctx: &Context, template: &Template| {
+        let mut out = String::new();
+        template.render(ctx, &mut out);
+        out
+    };
+    let out = render(&ctx, template);
+    let Some(narrow) = narrow else {
+        return out;
+    };
+
+    // Too wide for the terminal, it'd wrap; make room, a step at a time, until it fits.
+    let columns = crate::width::columns();
+    if fits(&out, columns) {
+        return out;
+    }
+    let drop = crate::config::CONFIG.lock().unwrap().drop_order().to_vec();
+    for name in drop.into_iter().filter(|name| template.uses(name)) {
+        ctx.hidden.push(name);
+        let out = render(&ctx, template);
+        if fits(&out, columns) {
+            return out;
+        }
+    }
+    crate::path::squeezed(|| {
+        // The directories are shown differently now.
+        ctx.rendered.borrow_mut().clear();
+        let out = render(&ctx, template);
+        if fits(&out, columns) {
+            return out;
+        }
+        // And last of all, everything on the one line. If even that won't fit, there's no more
+        // we can do.
+        render(&ctx, narrow)
+    })
+}
+
+/// Renders `$PS4`, which is done for every line `set -x` traces: the location is shown only if
+/// it's at hand already, and nothing's made to fit.
+fn render_traced(args: &Args, template: &Template) -> String {
+    let cwd = Cwd::current();
+    let cache = LOCATION_CACHE.lock().unwrap();
+    let location = match (cache.as_ref(), &cwd) {
+        (Some(c), Some(cwd)) if c.cwd == *cwd => Some(&c.location),
+        _ => None,
+    };
+    let mut out = String::new();
+    template.render(&context(args, location), &mut out);
+    out
+}
+
+/// Gathers up what the segments show, around `location`.
+fn context<'a>(args: &Args, location: Option<&'a Location>) -> Context<'a> {
     let uid_and_host = get_uid_and_host(args.default_username);
-    let location = get_location();
     let last_cmd_timing = LastCommandTiming::get();
-    let last_exit_status = LastCommandExitStatus::from_exit_status(args.last_exit_status);
+    let timing_info = TIMING_INFO.lock().unwrap();
+    let running = timing_info.last_start.map(|start| start.elapsed());
+    let last_exit_status = LastCommandExitStatus::from_exit_status(
+        timing_info
+            .last_exit_status
+            .unwrap_or(args.last_exit_status),
+    );
     let last_exit = LastCommandExit {
         status: last_exit_status,
-        end_dt: TIMING_INFO.lock().unwrap().last_end_dt,
+        end_dt: timing_info.last_end_dt,
     };
-    let key_mode_t = key_mode(args.vi_mode);
-    let cmd_sym = cmd_symbol();
-    print!("{uid_and_host}{location}\n{last_cmd_timing}{last_exit}{key_mode_t}{cmd_sym} ");
-    io::stdout().flush().unwrap();
-    Ok(())
+    drop(timing_info);
+    Context {
+        uid_and_host,
+        location,
+        timing: last_cmd_timing,
+        running,
+        exit: last_exit,
+        key_mode: key_mode(args.vi_mode),
+        symbol: cmd_symbol(),
+        palette: Palette::current(),
+        hidden: Vec::new(),
+        rendered: RefCell::new(HashMap::new()),
+    }
+}
+
+/// Whether any of the segments `template` shows needs the location.
+fn needs_location(template: &Template) -> bool {
+    template.uses("location") || template.uses("locks")
+}
+
+/// Whether `prompt` fits in a terminal `columns` wide, leaving some room to type in.
+fn fits(prompt: &str, columns: usize) -> bool {
+    let room = (columns / 4).min(20);
+    let lines = crate::width::lines(prompt, &escape_width);
+    let (last, rest) = lines.split_last().unwrap();
+    rest.iter().all(|&width| width <= columns) && last + room <= columns
+}
+
+/// How wide what zsh expands `%c` to is, near enough.
+fn escape_width(c: char) -> usize {
+    let param = |name| {
+        crate::escape::decode(&crate::zsh::param_bytes(name).unwrap_or_default()).into_owned()
+    };
+    let width = |s: &str| crate::width::lines(s, &|_| 0)[0];
+    match c {
+        'n' => width(&param(c"USERNAME")),
+        'M' => width(&param(c"HOST")),
+        'm' => width(param(c"HOST").split('.').next().unwrap_or("")),
+        '?' => param(c"?").len(),
+        'L' => param(c"SHLVL").len(),
+        'h' | '!' => param(c"HISTCMD").len(),
+        // All of it, rather than with `~` for `$HOME`: better too wide than too narrow.
+        '~' | '/' | 'd' => width(&param(c"PWD")),
+        '#' | 'j' => 1,
+        'T' => 5,
+        't' | '@' => 7,
+        '*' | 'D' | 'W' => 8,
+        // What's left is mostly for `$PS2`, `$PS4` and the like, which we don't fit.
+        _ => 0,
+    }
+}
+
+/// Everything the segments show, gathered up once per prompt.
+pub struct Context<'a> {
+    uid_and_host: UidAndHost,
+    /// `None` if the template doesn't show it.
+    location: Option<&'a Location>,
+    timing: LastCommandTiming,
+    /// How long the command that's running has been, if one is.
+    running: Option<Duration>,
+    exit: LastCommandExit,
+    key_mode: KeyMode,
+    symbol: CmdSymbol,
+    palette: Palette,
+    /// Segments left out, to make room.
+    hidden: Vec<&'static str>,
+    /// What each segment came to, the first time it was rendered: a template may well show one
+    /// more than once (`{?exit:{exit}}`), and we may render it a few times to make it fit.
+    rendered: RefCell<HashMap<&'static str, String>>,
+}
+
+impl Context<'_> {
+    /// Whether the segment called `name` is to be shown, or left out to make room.
+    pub fn shows(&self, name: &str) -> bool {
+        !self.hidden.contains(&name)
+    }
+
+    /// Renders `segment` onto `out`, or copies what it came to last time.
+    pub fn render(&self, segment: &Segment, out: &mut String) {
+        if let Some(rendered) = self.rendered.borrow().get(segment.name) {
+            out.push_str(rendered);
+            return;
+        }
+        let mut rendered = String::new();
+        self.palette
+            .paint_with(|| (segment.render)(self, &mut rendered));
+        out.push_str(&rendered);
+        self.rendered.borrow_mut().insert(segment.name, rendered);
+    }
+}
+
+/// Every segment there is, by the name templates know it by.
+pub static SEGMENTS: &[Segment] = &[
+    Segment {
+        name: "host",
+        render: |ctx, out| push(out, &ctx.uid_and_host),
+    },
+    Segment {
+        name: "location",
+        render: |ctx, out| {
+            if let Some(location) = ctx.location {
+                push(out, location);
+            }
+        },
+    },
+    Segment {
+        name: "locks",
+        render: |ctx, out| {
+            if let Some(Location::Repo(repo)) = ctx.location {
+                push(out, &StaleLocks(repo));
+            }
+        },
+    },
+    Segment {
+        name: "timing",
+        render: |ctx, out| push(out, &ctx.timing),
+    },
+    Segment {
+        name: "exit",
+        render: |ctx, out| push(out, &ctx.exit),
+    },
+    Segment {
+        name: "status",
+        render: |ctx, out| push(out, &ExitCode(&ctx.exit.status)),
+    },
+    Segment {
+        name: "clock",
+        render: |_ctx, out| out.push_str("%*"),
+    },
+    Segment {
+        name: "venv",
+        render: |_ctx, out| {
+            let venv = unsafe { crate::zsh::get_string_param(c"VIRTUAL_ENV") };
+            let name = venv
+                .map(|v| v.to_string_lossy())
+                .filter(|v| !v.is_empty())
+                .map(|v| {
+                    Path::new(&*v)
+                        .file_name()
+                        .map(|n| n.to_string_lossy().into_owned())
+                });
+            if let Some(Some(name)) = name {
+                let _ = write!(out, "({})", Untrusted(name));
+            }
+        },
+    },
+    Segment {
+        name: "parser",
+        render: |_ctx, out| push(out, &Paint(Role::Symbol, "%_")),
+    },
+    Segment {
+        name: "source",
+        render: |_ctx, out| push(out, &Paint(Role::Path, "%x:%I")),
+    },
+    Segment {
+        name: "elapsed",
+        render: |ctx, out| {
+            if let Some(elapsed) = ctx.running {
+                push(out, &Paint(Role::Warning, ElapsedTimePretty(&elapsed)));
+            }
+        },
+    },
+    Segment {
+        name: "typo",
+        render: |_ctx, out| push(out, &Paint(Role::Error, "%R")),
+    },
+    Segment {
+        name: "correction",
+        render: |_ctx, out| push(out, &Paint(Role::BranchOk, "%r")),
+    },
+    Segment {
+        name: "keymode",
+        render: |ctx, out| push(out, &ctx.key_mode),
+    },
+    Segment {
+        name: "symbol",
+        render: |ctx, out| push(out, &ctx.symbol),
+    },
+];
+
+fn push(out: &mut String, segment: &dyn fmt::Display) {
+    // Writing to a String can't fail.
+    let _ = write!(out, "{segment}");
 }
 
 struct Args<'a> {
@@ -101,11 +728,11 @@ struct Args<'a> {
     vi_mode: bool,
 }
 
-fn parse_args<'a>(args: &[&'a CStr]) -> Result<Args<'a>, i32> {
+fn parse_args<'a>(builtin: &str, args: &[&'a CStr]) -> Result<Args<'a>, i32> {
     if args.len() != 3 {
         eprintln!(
             "Usage:\n\
-             \t_rust-prompt-alpha \"$?\" \"$_PROMPT_ALPHA_DEFAULT_USER\" \"emacs|vi\""
+             \t{builtin} \"$?\" \"$_PROMPT_ALPHA_DEFAULT_USER\" \"emacs|vi\""
         );
         return Err(1);
     }
@@ -172,21 +799,21 @@ impl fmt::Display for UidAndHost {
         let output_at = !matches!(self.uid, PromptUid::Normal) || self.via_ssh;
 
         match self.uid {
-            PromptUid::Root => write!(f, "%B%F{{red}}%n%f%b")?,
+            PromptUid::Root => write!(f, "%B{}%b", Paint(Role::Root, "%n"))?,
             PromptUid::Normal => (),
-            PromptUid::OtherUser => write!(f, "%B%F{{yellow}}%n%f%b")?,
+            PromptUid::OtherUser => write!(f, "%B{}%b", Paint(Role::User, "%n"))?,
         }
 
         if output_at {
-            let color = match self.uid {
-                PromptUid::Normal => "green",
-                _ => "yellow",
+            let role = match self.uid {
+                PromptUid::Normal => Role::Host,
+                _ => Role::User,
             };
-            write!(f, "%F{{{color}}}@%f")?;
+            write!(f, "{}", Paint(role, "@"))?;
         }
 
         if self.via_ssh {
-            write!(f, "%B%F{{green}}%M%f%b")?;
+            write!(f, "%B{}%b", Paint(Role::Host, "%M"))?;
         }
 
         if output_at {
@@ -202,83 +829,6 @@ enum PromptUid {
     OtherUser,
 }
 
-fn get_location() -> Location {
-    let git_root = crate::git::show_toplevel();
-
-    if let Some(git_root) = git_root {
-        let branch = crate::git::get_branch(&git_root);
-
-        let prefix = crate::git::show_prefix();
-        let prefix = match prefix {
-            Ok(pr) => {
-                let mut bytes = pr.into_os_string().into_vec();
-                if bytes.last().copied() == Some(b'/') {
-                    bytes.pop();
-                }
-                Ok(PathBuf::from(OsString::from_vec(bytes)))
-            }
-            Err(err) => Err(err),
-        };
-
-        let repo = match git_root.file_name() {
-            Some(fname) => fname.to_string_lossy().into_owned(),
-            None => "???".to_owned(),
-        };
-
-        Location::Git {
-            repo,
-            branch: Branch(branch),
-            prefix,
-        }
-    } else {
-        Location::NotGit
-    }
-}
-
-struct Branch(anyhow::Result<crate::git::GitHead>);
-
-impl fmt::Display for Branch {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.0 {
-            Ok(crate::git::GitHead::Branch(b)) => write!(f, "%F{{green}}{b}%f"),
-            Ok(crate::git::GitHead::Detached(s)) => write!(f, "%F{{yellow}}(detached HEAD: {s})%f"),
-            Err(_err) => write!(f, "%B%F{{red}}(err)%f%b"),
-        }
-    }
-}
-
-enum Location {
-    Git {
-        repo: String,
-        branch: Branch,
-        prefix: anyhow::Result<PathBuf>,
-    },
-    NotGit,
-}
-
-impl fmt::Display for Location {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Location::Git {
-                repo,
-                branch,
-                prefix,
-            } => {
-                write!(f, "%F{{green}}±%f %B{repo}%b:{branch}:")?;
-                match prefix {
-                    Ok(p) => {
-                        let lossy = p.to_string_lossy();
-                        write!(f, "%B%F{{blue}}/{lossy}%f%b")?;
-                    }
-                    Err(_) => write!(f, "%B%F{{red}}(err)%f%b")?,
-                }
-                Ok(())
-            }
-            Location::NotGit => write!(f, "%B%F{{blue}}%~%f%b"),
-        }
-    }
-}
-
 /// Prints an indicator of what mode we're in: vi-normal, vi-insert, or emacs.
 fn key_mode(vi_mode: bool) -> KeyMode {
     let keymap = unsafe { crate::zsh::get_string_param(c"KEYMAP") };
@@ -292,7 +842,7 @@ fn key_mode(vi_mode: bool) -> KeyMode {
         KeyMode::Normal
     } else {
         let as_string = match keymap {
-            Some(v) => v.to_string_lossy().into_owned(),
+            Some(v) => crate::escape::decode(&crate::zsh::unmetafy(v)).into_owned(),
             None => "< $KEYMAP unset >".to_owned(),
         };
         KeyMode::Unknown(as_string)
@@ -310,16 +860,16 @@ impl fmt::Display for KeyMode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             KeyMode::Emacs => Ok(()),
-            KeyMode::Insert => write!(f, "[%{{\x1b[92m%}}i%{{\x1b[0m%}}] "),
-            KeyMode::Normal => write!(f, "[%{{\x1b[93m%}}n%{{\x1b[0m%}}] "),
-            KeyMode::Unknown(map) => write!(f, "[? ({map})] "),
+            KeyMode::Insert => write!(f, "[{}] ", Paint(Role::InsertMode, "i")),
+            KeyMode::Normal => write!(f, "[{}] ", Paint(Role::NormalMode, "n")),
+            KeyMode::Unknown(map) => write!(f, "[? ({})] ", Untrusted(map)),
         }
     }
 }
 
 enum LastCommandExitStatus {
     Success,
-    Error,
+    Error(i32),
     Signal(Signal),
 }
 
@@ -333,7 +883,7 @@ impl LastCommandExitStatus {
             }
         }
 
-        LastCommandExitStatus::Error
+        LastCommandExitStatus::Error(exit_status)
     }
 }
 
@@ -346,19 +896,47 @@ impl fmt::Display for LastCommandExit {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.status {
             LastCommandExitStatus::Success => Ok(()),
-            LastCommandExitStatus::Error => {
+            LastCommandExitStatus::Error(status) => {
+                // Not `%?`: by the time a worker's result redraws the prompt, that's some widget's.
+                let message = format_args!(
+                    "%B(last command returned {status}{}%)%b",
+                    LastCommandEnd(self.end_dt.as_ref()),
+                );
+                write!(f, "{}", Paint(Role::Error, message))
+            }
+            LastCommandExitStatus::Signal(signal) => {
+                let message = format_args!(
+                    "%B(last command got signal {}{})%b",
+                    signal.as_str(),
+                    LastCommandEnd(self.end_dt.as_ref()),
+                );
+                write!(f, "{}", Paint(Role::Error, message))
+            }
+        }
+    }
+}
+
+/// Just the exit status, or the signal; for when there isn't room for the rest.
+struct ExitCode<'a>(&'a LastCommandExitStatus);
+
+impl fmt::Display for ExitCode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            LastCommandExitStatus::Success => Ok(()),
+            LastCommandExitStatus::Error(status) => {
+                let failed = crate::glyphs::get().failed;
                 write!(
                     f,
-                    "%F{{red}}%B(last command returned %?{}%)%b%f\n",
-                    LastCommandEnd(self.end_dt.as_ref()),
+                    "{}",
+                    Paint(Role::Error, format_args!("{failed}{status}"))
                 )
             }
             LastCommandExitStatus::Signal(signal) => {
+                let failed = crate::glyphs::get().failed;
                 write!(
                     f,
-                    "%F{{red}}%B(last command got signal {}{})%b%f\n",
-                    signal.as_str(),
-                    LastCommandEnd(self.end_dt.as_ref()),
+                    "{}",
+                    Paint(Role::Error, format_args!("{failed}{}", signal.as_str()))
                 )
             }
         }
@@ -397,7 +975,8 @@ impl fmt::Display for LastCommandTiming {
         if let Some(duration) = self.elapsed.as_ref() {
             let threshold = *TIMING_THRESHOLD_MS.lock().unwrap();
             if u128::from(threshold) <= duration.as_millis() {
-                write!(f, "⏱{}\n", ElapsedTimePretty(duration))
+                let timer = crate::glyphs::get().timer;
+                write!(f, "{timer}{}", ElapsedTimePretty(duration))
             } else {
                 Ok(())
             }
@@ -447,7 +1026,7 @@ impl fmt::Display for ElapsedTimePretty<'_> {
     }
 }
 
-/// Emit either a red '#' if we're root, or a blue '»' otherwise.
+/// Emit either a red '#' if we're root, or a blue '»' (or whatever the glyphs have) otherwise.
 fn cmd_symbol() -> CmdSymbol {
     let uid = nix::unistd::getuid();
     if uid.is_root() {
@@ -465,8 +1044,11 @@ enum CmdSymbol {
 impl fmt::Display for CmdSymbol {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            CmdSymbol::Root => write!(f, "%F{{red}}%B#%b%f"),
-            CmdSymbol::NotRoot => write!(f, "%{{\x1b[1;38;5;033m%}}»%{{\x1b[0m%}}"),
+            CmdSymbol::Root => write!(f, "%B{}%b", Paint(Role::Root, "#")),
+            CmdSymbol::NotRoot => {
+                let symbol = crate::glyphs::get().symbol;
+                write!(f, "%B{}%b", Paint(Role::Symbol, symbol))
+            }
         }
     }
 }
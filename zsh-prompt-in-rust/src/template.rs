@@ -0,0 +1,268 @@
+//! A small language for laying the prompt out, so that rearranging it doesn't take a rebuild.
+//!
+//! `{name}` is replaced with the segment of that name (see `prompt::SEGMENTS`). `{?name:…}` is
+//! whatever `…` is, but only when that segment has something to show; `{!name:…}`, only when it
+//! doesn't. Everything else is literal, zsh's own `%` escapes included; `\n` is a newline, and
+//! `\{`, `\}` and `\\` stand for themselves.
+//!
+//! Templates are parsed as they're set, so that a typo is reported then, and not on every prompt.
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::prompt::{Context, SEGMENTS};
+
+/// A named piece of the prompt.
+pub struct Segment {
+    pub name: &'static str,
+    pub render: fn(&Context, &mut String),
+}
+
+#[derive(Clone)]
+pub struct Template(Vec<Node>);
+
+#[derive(Clone)]
+enum Node {
+    Literal(String),
+    Segment(&'static Segment),
+    /// `{?name:…}`, or with `shown` false, `{!name:…}`.
+    Conditional {
+        segment: &'static Segment,
+        shown: bool,
+        body: Template,
+    },
+}
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Template, String> {
+        parse_nodes(&mut s.chars().peekable(), SEGMENTS, false)
+    }
+
+    /// Whether the segment called `name` appears anywhere in here.
+    pub fn uses(&self, name: &str) -> bool {
+        self.0.iter().any(|node| match node {
+            Node::Literal(_) => false,
+            Node::Segment(segment) => segment.name == name,
+            Node::Conditional { segment, body, .. } => segment.name == name || body.uses(name),
+        })
+    }
+
+    pub fn render(&self, ctx: &Context, out: &mut String) {
+        for node in &self.0 {
+            match node {
+                Node::Literal(s) => out.push_str(s),
+                Node::Segment(segment) if ctx.shows(segment.name) => ctx.render(segment, out),
+                Node::Segment(_) => {}
+                Node::Conditional {
+                    segment,
+                    shown,
+                    body,
+                } => {
+                    let mut rendered = String::new();
+                    if ctx.shows(segment.name) {
+                        ctx.render(segment, &mut rendered);
+                    }
+                    if rendered.is_empty() != *shown {
+                        body.render(ctx, out);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Parses up to the end of the template, or with `nested`, up to the `}` that closes the
+/// conditional we're in the body of. The names are looked up in `segments`.
+fn parse_nodes(
+    chars: &mut Peekable<Chars>,
+    segments: &'static [Segment],
+    nested: bool,
+) -> Result<Template, String> {
+    let mut nodes = Vec::new();
+    let mut literal = String::new();
+    loop {
+        match chars.next() {
+            None if nested => return Err("a {?…} or {!…} is missing its closing }".to_owned()),
+            None => break,
+            Some('\\') => match chars.next() {
+                Some('n') => literal.push('\n'),
+                Some(c @ ('\\' | '{' | '}')) => literal.push(c),
+                // Not one of ours; leave it be.
+                Some(c) => {
+                    literal.push('\\');
+                    literal.push(c);
+                }
+                None => literal.push('\\'),
+            },
+            Some('}') if nested => break,
+            Some('}') => return Err("unmatched }; a literal one is written \\}".to_owned()),
+            Some('{') => {
+                if !literal.is_empty() {
+                    nodes.push(Node::Literal(std::mem::take(&mut literal)));
+                }
+                let shown = match chars.peek() {
+                    Some('?') => Some(true),
+                    Some('!') => Some(false),
+                    _ => None,
+                };
+                let node = match shown {
+                    Some(shown) => {
+                        chars.next();
+                        let segment = lookup(segments, &parse_name(chars, ':')?)?;
+                        let body = parse_nodes(chars, segments, true)?;
+                        Node::Conditional {
+                            segment,
+                            shown,
+                            body,
+                        }
+                    }
+                    None => Node::Segment(lookup(segments, &parse_name(chars, '}')?)?),
+                };
+                nodes.push(node);
+            }
+            Some(c) => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        nodes.push(Node::Literal(literal));
+    }
+    Ok(Template(nodes))
+}
+
+/// Reads a segment's name, and the `end` after it.
+fn parse_name(chars: &mut Peekable<Chars>, end: char) -> Result<String, String> {
+    let mut name = String::new();
+    loop {
+        match chars.next() {
+            Some(c) if c == end => return Ok(name),
+            Some(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_' => name.push(c),
+            Some(c) => return Err(format!("expected {end:?} after {{{name}, not {c:?}")),
+            None => return Err(format!("expected {end:?} after {{{name}")),
+        }
+    }
+}
+
+fn lookup(segments: &'static [Segment], name: &str) -> Result<&'static Segment, String> {
+    segments.iter().find(|s| s.name == name).ok_or_else(|| {
+        let known: Vec<_> = segments.iter().map(|s| s.name).collect();
+        format!(
+            "there's no segment called {name:?}; there's {}",
+            known.join(", ")
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stand-ins for the real segments, which can't be rendered outside of zsh.
+    static SEGMENTS: &[Segment] = &[
+        Segment {
+            name: "host",
+            render: |_ctx, _out| {},
+        },
+        Segment {
+            name: "location",
+            render: |_ctx, _out| {},
+        },
+        Segment {
+            name: "exit",
+            render: |_ctx, _out| {},
+        },
+    ];
+
+    fn parse(s: &str) -> Result<Template, String> {
+        parse_nodes(&mut s.chars().peekable(), SEGMENTS, false)
+    }
+
+    fn error(s: &str) -> String {
+        match parse(s) {
+            Ok(template) => panic!("{s:?} parsed, as {:?}", names(&template)),
+            Err(err) => err,
+        }
+    }
+
+    fn names(template: &Template) -> Vec<String> {
+        template
+            .0
+            .iter()
+            .map(|node| match node {
+                Node::Literal(s) => format!("{s:?}"),
+                Node::Segment(segment) => segment.name.to_owned(),
+                Node::Conditional {
+                    segment,
+                    shown,
+                    body,
+                } => {
+                    let mark = if *shown { '?' } else { '!' };
+                    format!("{mark}{}[{}]", segment.name, names(body).join(" "))
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn segments_and_literals() {
+        let template = parse("{host}@{location} %# ").unwrap();
+        assert_eq!(names(&template), ["host", "\"@\"", "location", "\" %# \""]);
+        assert!(template.uses("location"));
+        assert!(!template.uses("exit"));
+        assert!(names(&parse("").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn escapes() {
+        let template = parse(r"a\nb\{c\}\\d\x%\{\e[1m%\}").unwrap();
+        assert_eq!(
+            names(&template),
+            [format!("{:?}", "a\nb{c}\\d\\x%{\\e[1m%}")]
+        );
+        // A trailing backslash is just a backslash.
+        assert_eq!(names(&parse("x\\").unwrap()), ["\"x\\\\\""]);
+    }
+
+    #[test]
+    fn conditionals_nest() {
+        let template = parse("{?location:[{location}{!exit:ok{?host: on {host}}}]}\\n").unwrap();
+        assert_eq!(
+            names(&template),
+            [
+                "?location[\"[\" location !exit[\"ok\" ?host[\" on \" host]] \"]\"]",
+                "\"\\n\"",
+            ]
+        );
+        // Used anywhere within, it's used.
+        assert!(template.uses("host"));
+        assert!(template.uses("exit"));
+        // A `}` closes the innermost conditional, and no more.
+        let err = error("{?host:a}b}");
+        assert!(err.contains("unmatched }"), "{err}");
+    }
+
+    #[test]
+    fn unknown_segments() {
+        for s in ["{nope}", "{?nope:x}", "{!nope:x}", "{?host:{nope}}"] {
+            let err = error(s);
+            assert!(err.contains("\"nope\""), "{s}: {err}");
+            assert!(err.contains("host, location, exit"), "{s}: {err}");
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        for (s, expected) in [
+            ("{?host:x", "missing its closing }"),
+            ("{?host:{?exit:x}", "missing its closing }"),
+            ("{host", "expected '}' after {host"),
+            ("{?host", "expected ':' after {host"),
+            ("{ho st}", "expected '}' after {ho, not ' '"),
+            ("{}", "there's no segment called \"\""),
+            ("}", "unmatched }"),
+            ("{host}}", "unmatched }"),
+        ] {
+            let err = error(s);
+            assert!(err.contains(expected), "{s:?}: {err}");
+        }
+    }
+}
@@ -0,0 +1,468 @@
+//! Colors, by what they're for, rather than what they are.
+//!
+//! Each part of the prompt is painted in a role's color, and a theme says what color each role
+//! is. The color is then brought down to what the terminal can show: `$COLORTERM` says whether it
+//! can do 24-bit color, `$TERM`'s terminfo entry how many colors it can do otherwise; and if
+//! `$NO_COLOR` is set, there's no color at all. That's all looked up once per prompt, into a
+//! `Palette`.
+
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::config::CONFIG;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    /// One of the 16 colors the terminal's own palette defines.
+    Ansi(u8),
+    /// One of xterm's 256.
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    /// A name (`red`, `bright-blue`), a number up to 255, or `#rrggbb`.
+    pub fn parse(s: &str) -> Option<Color> {
+        if let Some(hex) = s.strip_prefix('#') {
+            if hex.len() != 6 || !hex.is_ascii() {
+                return None;
+            }
+            let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+            return Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?));
+        }
+        if let Ok(n) = s.parse::<u8>() {
+            return Some(if n < 16 {
+                Color::Ansi(n)
+            } else {
+                Color::Indexed(n)
+            });
+        }
+        let (bright, name) = match s.strip_prefix("bright-") {
+            Some(name) => (8, name),
+            None => (0, s),
+        };
+        let n = ANSI_NAMES.iter().position(|n| *n == name)?;
+        Some(Color::Ansi(bright + n as u8))
+    }
+
+    /// This color, or the nearest one to it, in a terminal that can show `depth`.
+    fn downgrade(self, depth: Depth) -> Color {
+        match (self, depth) {
+            (Color::Rgb(r, g, b), Depth::Indexed) => Color::Indexed(rgb_to_indexed(r, g, b)),
+            (Color::Rgb(..) | Color::Indexed(_), Depth::Ansi) => {
+                let (r, g, b) = self.rgb();
+                let nearest = (0..16)
+                    .min_by_key(|&n| distance(Color::Ansi(n).rgb(), (r, g, b)))
+                    .unwrap();
+                Color::Ansi(nearest)
+            }
+            _ => self,
+        }
+    }
+
+    /// Roughly what this looks like; the 16 colors are whatever the terminal says they are, so for
+    /// them, this goes by xterm's defaults.
+    fn rgb(self) -> (u8, u8, u8) {
+        const ANSI: [(u8, u8, u8); 16] = [
+            (0, 0, 0),
+            (205, 0, 0),
+            (0, 205, 0),
+            (205, 205, 0),
+            (0, 0, 238),
+            (205, 0, 205),
+            (0, 205, 205),
+            (229, 229, 229),
+            (127, 127, 127),
+            (255, 0, 0),
+            (0, 255, 0),
+            (255, 255, 0),
+            (92, 92, 255),
+            (255, 0, 255),
+            (0, 255, 255),
+            (255, 255, 255),
+        ];
+        match self {
+            Color::Ansi(n) | Color::Indexed(n @ 0..=15) => ANSI[usize::from(n)],
+            Color::Indexed(n @ 16..=231) => {
+                let n = n - 16;
+                (
+                    CUBE[n as usize / 36],
+                    CUBE[n as usize / 6 % 6],
+                    CUBE[n as usize % 6],
+                )
+            }
+            Color::Indexed(n) => {
+                let level = 8 + 10 * (n - 232);
+                (level, level, level)
+            }
+            Color::Rgb(r, g, b) => (r, g, b),
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    /// As zsh's `%F{…}` takes it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Color::Ansi(n) | Color::Indexed(n) => write!(f, "{n}"),
+            Color::Rgb(r, g, b) => write!(f, "#{r:02x}{g:02x}{b:02x}"),
+        }
+    }
+}
+
+const ANSI_NAMES: [&str; 8] = [
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+/// The levels each channel of xterm's 6×6×6 color cube can take.
+const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn rgb_to_indexed(r: u8, g: u8, b: u8) -> u8 {
+    let level = |v: u8| match v {
+        0..48 => 0,
+        48..115 => 1,
+        _ => (v - 35) / 40,
+    };
+    let (cr, cg, cb) = (level(r), level(g), level(b));
+    let cube = 16 + 36 * cr + 6 * cg + cb;
+
+    // The grayscale ramp is finer than the cube's grays, so it may well be closer.
+    let average = (u16::from(r) + u16::from(g) + u16::from(b)) / 3;
+    let gray = 232 + (average.saturating_sub(3) / 10).min(23) as u8;
+
+    let target = (r, g, b);
+    if distance(Color::Indexed(gray).rgb(), target) < distance(Color::Indexed(cube).rgb(), target) {
+        gray
+    } else {
+        cube
+    }
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (i32::from(x) - i32::from(y)).unsigned_abs().pow(2);
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+/// How many colors the terminal can show.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    /// `$NO_COLOR`.
+    None,
+    Ansi,
+    Indexed,
+    Truecolor,
+}
+
+impl Depth {
+    pub fn parse(s: &str) -> Option<Depth> {
+        match s {
+            "none" => Some(Depth::None),
+            "16" => Some(Depth::Ansi),
+            "256" => Some(Depth::Indexed),
+            "truecolor" => Some(Depth::Truecolor),
+            _ => None,
+        }
+    }
+
+    fn detect() -> Depth {
+        // https://no-color.org/: set to anything at all, save for empty.
+        if param(c"NO_COLOR").is_some() {
+            return Depth::None;
+        }
+        if matches!(param(c"COLORTERM").as_deref(), Some("truecolor" | "24bit")) {
+            return Depth::Truecolor;
+        }
+        let Some(term) = param(c"TERM") else {
+            return Depth::Ansi;
+        };
+        match terminfo_colors(&term) {
+            Some(256..) => Depth::Indexed,
+            Some(8..) => Depth::Ansi,
+            Some(_) => Depth::None,
+            // No terminfo entry to go by, so guess from the name.
+            None if term == "dumb" => Depth::None,
+            None if term.contains("256color") => Depth::Indexed,
+            None => Depth::Ansi,
+        }
+    }
+}
+
+fn param(name: &CStr) -> Option<String> {
+    crate::zsh::param_bytes(name)
+        .and_then(|b| String::from_utf8(b).ok())
+        .filter(|s| !s.is_empty())
+}
+
+/// The `colors` capability of `term`'s terminfo entry, if there's an entry; an entry without it
+/// is for a terminal that has no colors, and comes to 0.
+fn terminfo_colors(term: &str) -> Option<i32> {
+    let first = term.chars().next()?;
+    if term.contains('/') {
+        return None;
+    }
+    // Where ncurses looks, in the order it looks there.
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    dirs.extend(param(c"TERMINFO").map(PathBuf::from));
+    dirs.extend(param(c"HOME").map(|home| PathBuf::from(home).join(".terminfo")));
+    let defaults = ["/etc/terminfo", "/lib/terminfo", "/usr/share/terminfo"];
+    match param(c"TERMINFO_DIRS") {
+        Some(list) => {
+            for dir in list.split(':') {
+                // An empty one stands for the defaults.
+                match dir {
+                    "" => dirs.extend(defaults.map(PathBuf::from)),
+                    dir => dirs.push(PathBuf::from(dir)),
+                }
+            }
+        }
+        None => dirs.extend(defaults.map(PathBuf::from)),
+    }
+    dirs.iter().find_map(|dir| {
+        // Filed under its first letter; or on case-insensitive filesystems, that letter in hex.
+        [first.to_string(), format!("{:x}", u32::from(first))]
+            .iter()
+            .find_map(|sub| std::fs::read(dir.join(sub).join(term)).ok())
+            .map(|entry| entry_colors(&entry).unwrap_or(0))
+    })
+}
+
+/// Finds `colors` in a compiled terminfo entry (see term(5)). `None` if it isn't there.
+fn entry_colors(entry: &[u8]) -> Option<i32> {
+    /// Where `colors` is among the numeric capabilities.
+    const COLORS: usize = 13;
+    let short = |i: usize| -> Option<usize> {
+        let bytes = entry.get(2 * i..2 * i + 2)?;
+        Some(usize::from(u16::from_le_bytes([bytes[0], bytes[1]])))
+    };
+    // The original format has 16-bit numbers; the extended one, 32-bit ones.
+    let number_size = match short(0)? {
+        0o432 => 2,
+        0o1036 => 4,
+        _ => return None,
+    };
+    let (names, bools, numbers) = (short(1)?, short(2)?, short(3)?);
+    if numbers <= COLORS {
+        return None;
+    }
+    // The numbers start on an even byte.
+    let start = (12 + names + bools).next_multiple_of(2) + COLORS * number_size;
+    let bytes = entry.get(start..start + number_size)?;
+    let colors = match number_size {
+        2 => i32::from(i16::from_le_bytes([bytes[0], bytes[1]])),
+        _ => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    };
+    // Negative, for a capability that's absent (or cancelled).
+    (0 <= colors).then_some(colors)
+}
+
+/// What a color is for.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The mark saying which kind of repository we're in.
+    Repo,
+    BranchOk,
+    BranchDetached,
+    Bookmark,
+    Revision,
+    /// jj's change ID.
+    Change,
+    Dirty,
+    Warning,
+    Error,
+    Path,
+    /// A caveat about where we are, rather than a problem: that it's ignored, say.
+    Ignored,
+    Root,
+    /// Some user other than the usual one.
+    User,
+    Host,
+    InsertMode,
+    NormalMode,
+    Symbol,
+}
+
+impl Role {
+    const ALL: [Role; 17] = [
+        Role::Repo,
+        Role::BranchOk,
+        Role::BranchDetached,
+        Role::Bookmark,
+        Role::Revision,
+        Role::Change,
+        Role::Dirty,
+        Role::Warning,
+        Role::Error,
+        Role::Path,
+        Role::Ignored,
+        Role::Root,
+        Role::User,
+        Role::Host,
+        Role::InsertMode,
+        Role::NormalMode,
+        Role::Symbol,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Repo => "repo",
+            Role::BranchOk => "branch-ok",
+            Role::BranchDetached => "branch-detached",
+            Role::Bookmark => "bookmark",
+            Role::Revision => "revision",
+            Role::Change => "change",
+            Role::Dirty => "dirty",
+            Role::Warning => "warning",
+            Role::Error => "error",
+            Role::Path => "path",
+            Role::Ignored => "ignored",
+            Role::Root => "root",
+            Role::User => "user",
+            Role::Host => "host",
+            Role::InsertMode => "insert-mode",
+            Role::NormalMode => "normal-mode",
+            Role::Symbol => "symbol",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Role> {
+        Role::ALL.into_iter().find(|r| r.as_str() == s)
+    }
+}
+
+/// A color for each role, in the order of `Role::ALL`.
+#[derive(Clone)]
+pub struct Theme([Color; Role::ALL.len()]);
+
+impl Theme {
+    /// The colors this prompt has always had.
+    pub const DEFAULT: Theme = Theme([
+        Color::Ansi(2),
+        Color::Ansi(2),
+        Color::Ansi(3),
+        Color::Ansi(6),
+        Color::Ansi(3),
+        Color::Ansi(5),
+        Color::Ansi(1),
+        Color::Ansi(3),
+        Color::Ansi(1),
+        Color::Ansi(4),
+        Color::Ansi(8),
+        Color::Ansi(1),
+        Color::Ansi(3),
+        Color::Ansi(2),
+        Color::Ansi(10),
+        Color::Ansi(11),
+        Color::Indexed(33),
+    ]);
+
+    /// Gruvbox's dark palette.
+    const GRUVBOX: Theme = Theme([
+        Color::Rgb(0xb8, 0xbb, 0x26),
+        Color::Rgb(0xb8, 0xbb, 0x26),
+        Color::Rgb(0xfa, 0xbd, 0x2f),
+        Color::Rgb(0x8e, 0xc0, 0x7c),
+        Color::Rgb(0xfa, 0xbd, 0x2f),
+        Color::Rgb(0xd3, 0x86, 0x9b),
+        Color::Rgb(0xfb, 0x49, 0x34),
+        Color::Rgb(0xfe, 0x80, 0x19),
+        Color::Rgb(0xfb, 0x49, 0x34),
+        Color::Rgb(0x83, 0xa5, 0x98),
+        Color::Rgb(0x92, 0x83, 0x74),
+        Color::Rgb(0xfb, 0x49, 0x34),
+        Color::Rgb(0xfa, 0xbd, 0x2f),
+        Color::Rgb(0xb8, 0xbb, 0x26),
+        Color::Rgb(0xb8, 0xbb, 0x26),
+        Color::Rgb(0xfa, 0xbd, 0x2f),
+        Color::Rgb(0x83, 0xa5, 0x98),
+    ]);
+
+    pub fn named(name: &str) -> Option<Theme> {
+        match name {
+            "default" => Some(Theme::DEFAULT),
+            "gruvbox" => Some(Theme::GRUVBOX),
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, role: Role, color: Color) {
+        let index = Role::ALL.iter().position(|r| *r == role).unwrap();
+        self.0[index] = color;
+    }
+
+    fn get(&self, role: Role) -> Color {
+        let index = Role::ALL.iter().position(|r| *r == role).unwrap();
+        self.0[index]
+    }
+}
+
+/// The theme, brought down to what the terminal can show; `None` for no colors at all.
+#[derive(Clone)]
+pub struct Palette(Option<Theme>);
+
+/// The palette, as looked up for the first prompt since the last `pre_cmd`; `$PS4` is rendered for
+/// every line `set -x` traces, and the terminfo entry needn't be read for each.
+static CURRENT: Mutex<Option<Palette>> = Mutex::new(None);
+
+impl Palette {
+    /// The theme, brought down to what the terminal can show; looked up again only after
+    /// `invalidate`.
+    pub fn current() -> Palette {
+        CURRENT
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Palette::look_up)
+            .clone()
+    }
+
+    /// Has the next `current` look everything up again: from `pre_cmd`, since `$TERM` may have
+    /// changed, and whenever the theme does.
+    pub fn invalidate() {
+        *CURRENT.lock().unwrap() = None;
+    }
+
+    fn look_up() -> Palette {
+        let (theme, depth) = {
+            let config = CONFIG.lock().unwrap();
+            (config.theme.clone(), config.color_depth)
+        };
+        match depth.unwrap_or_else(Depth::detect) {
+            Depth::None => Palette(None),
+            depth => Palette(Some(Theme(theme.0.map(|color| color.downgrade(depth))))),
+        }
+    }
+
+    fn get(&self, role: Role) -> Option<Color> {
+        self.0.as_ref().map(|theme| theme.get(role))
+    }
+
+    /// Runs `f` with everything it paints in this palette.
+    pub fn paint_with<R>(&self, f: impl FnOnce() -> R) -> R {
+        let outer = PALETTE.replace(Some(self.clone()));
+        let result = f();
+        PALETTE.set(outer);
+        result
+    }
+}
+
+thread_local! {
+    /// The palette the prompt being rendered is painted in.
+    static PALETTE: RefCell<Option<Palette>> = const { RefCell::new(None) };
+}
+
+/// Shows `.1` in the color of the role `.0`.
+pub struct Paint<T>(pub Role, pub T);
+
+impl<T: fmt::Display> fmt::Display for Paint<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let color = PALETTE.with_borrow(|palette| palette.as_ref().map(|p| p.get(self.0)));
+        // Painted outside of a prompt, which shouldn't happen.
+        let color = color.unwrap_or_else(|| Palette::current().get(self.0));
+        match color {
+            Some(color) => write!(f, "%F{{{color}}}{}%f", self.1),
+            None => write!(f, "{}", self.1),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! The terminal's title: where we are while we're at the prompt, and what's running while it runs.
+//!
+//! The title is set with xterm's OSC 2 (or OSC 0, which sets the icon name too), or inside screen,
+//! with its `\ek…\e\\`, which names the window. (tmux ignores that unless `allow-rename` is on, but
+//! takes OSC 2 as the pane's title.) Whatever goes in it has its control characters made visible
+//! (see `escape`), lest a command line (or a directory's name) that has some end the escape
+//! sequence early, and go on to say something else to the terminal.
+
+use std::os::unix::ffi::OsStrExt;
+
+use crate::config::CONFIG;
+use crate::escape;
+use crate::location::Location;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Off,
+    /// Screen's, when `$TERM` says it's screen (and `$TMUX`, which uses the same `$TERM`, isn't
+    /// set); xterm's OSC 2 otherwise.
+    Auto,
+    /// OSC 0: the window's title, and its icon's name.
+    Osc0,
+    /// OSC 2: the window's title.
+    Osc2,
+    /// `\ek…\e\\`: the name of a tmux or screen window.
+    Screen,
+}
+
+impl Mode {
+    pub fn parse(s: &str) -> Option<Mode> {
+        match s {
+            "off" => Some(Mode::Off),
+            "auto" => Some(Mode::Auto),
+            "osc0" => Some(Mode::Osc0),
+            "osc2" => Some(Mode::Osc2),
+            "screen" => Some(Mode::Screen),
+            _ => None,
+        }
+    }
+}
+
+/// Sets the title for the prompt, from `title-format`.
+pub fn at_prompt(location: Option<&Location>) {
+    let format = CONFIG.lock().unwrap().title_format().to_owned();
+    let here = match location {
+        Some(Location::Repo(repo)) => match &repo.prefix {
+            Ok(prefix) => format!(
+                "{}:/{}",
+                repo.name,
+                escape::decode(prefix.as_os_str().as_bytes())
+            ),
+            Err(_) => repo.name.clone(),
+        },
+        _ => crate::path::cwd_whole().plain(),
+    };
+    set(&expand(&format, &here, ""));
+}
+
+/// Sets the title while `command` runs, from `title-command-format`.
+pub fn at_exec(command: &str) {
+    let format = CONFIG.lock().unwrap().title_command_format().to_owned();
+    // Only the first line of a long one: a title is one line.
+    let command = command.lines().next().unwrap_or("");
+    set(&expand(&format, &crate::path::cwd_whole().plain(), command));
+}
+
+/// Replaces `{location}`, `{command}`, `{host}` and `{user}` in `format`; anything else is left as
+/// it is.
+fn expand(format: &str, location: &str, command: &str) -> String {
+    let param =
+        |name| escape::decode(&crate::zsh::param_bytes(name).unwrap_or_default()).into_owned();
+    let mut out = String::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        match &rest[1..end] {
+            "location" => out.push_str(location),
+            "command" => out.push_str(command),
+            // As `%m` has it: up to the first dot.
+            "host" => out.push_str(param(c"HOST").split('.').next().unwrap_or("")),
+            "user" => out.push_str(&param(c"USERNAME")),
+            _ => out.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn set(title: &str) {
+    let mode = match CONFIG.lock().unwrap().title {
+        Mode::Auto => {
+            let term = crate::zsh::param_bytes(c"TERM").unwrap_or_default();
+            if crate::zsh::param_bytes(c"TMUX").is_none() && term.starts_with(b"screen") {
+                Mode::Screen
+            } else {
+                Mode::Osc2
+            }
+        }
+        mode => mode,
+    };
+    let (start, end) = match mode {
+        Mode::Off | Mode::Auto => return,
+        Mode::Osc0 => ("\x1b]0;", "\x1b\\"),
+        Mode::Osc2 => ("\x1b]2;", "\x1b\\"),
+        Mode::Screen => ("\x1bk", "\x1b\\"),
+    };
+    let out = format!("{start}{}{end}", crate::escape::controls(title));
+    crate::osc::send(&out);
+}
@@ -0,0 +1,229 @@
+//! What the location segment needs from a version control system, whichever one it is.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+
+use crate::process::TimedOut;
+pub use crate::watch::StateDir;
+
+/// Which version control system a repository belongs to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Git,
+    Hg,
+    Jj,
+}
+
+impl Kind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::Git => "git",
+            Kind::Hg => "hg",
+            Kind::Jj => "jj",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Kind> {
+        [Kind::Git, Kind::Hg, Kind::Jj]
+            .into_iter()
+            .find(|k| k.as_str() == s)
+    }
+
+    /// Marks the location segment as being in a repository of this kind.
+    pub fn glyph(self) -> &'static str {
+        let glyphs = crate::glyphs::get();
+        match self {
+            Kind::Git => glyphs.git,
+            Kind::Hg => glyphs.hg,
+            Kind::Jj => glyphs.jj,
+        }
+    }
+
+    pub fn backend(self) -> &'static dyn Backend {
+        *BACKENDS.iter().find(|b| b.kind() == self).unwrap()
+    }
+}
+
+pub trait Backend: Sync {
+    fn kind(&self) -> Kind;
+
+    /// Finds the root of the repository `dir` is in, if it's in one.
+    fn discover(&self, dir: &Path, deadline: Instant) -> Result<Option<PathBuf>, TimedOut>;
+
+    /// The directories holding the repository's own state, which get watched for changes: just
+    /// those where a change means what's shown has changed, and not (say) the object store.
+    fn state_dirs(&self, root: &Path) -> Vec<StateDir>;
+
+    /// What's checked out.
+    fn head(&self, root: &Path, deadline: Instant) -> anyhow::Result<Head>;
+
+    /// Whether the working copy has uncommitted changes; `None` if that can't be told cheaply.
+    fn dirty(&self, root: &Path, deadline: Instant) -> anyhow::Result<Option<bool>>;
+
+    /// Whether `prefix`, a directory in the working copy, is one the repository ignores.
+    fn ignored(&self, root: &Path, prefix: &Path, deadline: Instant) -> anyhow::Result<bool>;
+
+    /// The directories in the working copy that the repository ignores (`target/`,
+    /// `node_modules/`), which aren't worth watching. Only the outermost of them: what's under an
+    /// ignored directory is left out. Empty if there's no cheap way to tell.
+    fn ignored_dirs(&self, root: &Path, deadline: Instant) -> anyhow::Result<Vec<PathBuf>>;
+
+    /// How many of the commits that have yet to be pushed are meant to be squashed away before
+    /// they are (`fixup!` and friends, or works in progress). `None` if there's nothing to tell
+    /// which those are.
+    fn unsquashed(&self, root: &Path, deadline: Instant) -> anyhow::Result<Option<Unsquashed>>;
+
+    /// The multi-step operation the repository is in the middle of, if any.
+    fn operation(&self, root: &Path) -> anyhow::Result<Option<Operation>>;
+
+    /// The lock files in the repository's state directory. Any that stick around were most likely
+    /// left behind by a process that died holding them.
+    fn locks(&self, root: &Path) -> anyhow::Result<Vec<Lock>>;
+
+    /// Where the repository was cloned from, if it's a clone.
+    fn remote(&self, root: &Path) -> Option<String>;
+}
+
+/// Every backend we know of. When repositories are nested, the innermost one wins; when two share
+/// a root, the earlier one here does. (jj is usually colocated with git, and when it is, git's
+/// view of things, with HEAD always detached, isn't the interesting one.)
+pub static BACKENDS: &[&dyn Backend] = &[&crate::jj::Jj, &crate::git::Git, &crate::hg::Hg];
+
+pub enum Head {
+    /// The branch's name, as the bytes it was made with; UTF-8 or not, it's only decoded to be
+    /// shown.
+    Branch(Vec<u8>),
+    /// What HEAD holds, as it holds it.
+    Detached(Vec<u8>),
+    /// Mercurial's working copy parent: always on a named branch, and maybe with an active
+    /// bookmark. Both are bytes, as `Branch` is.
+    Hg {
+        branch: Vec<u8>,
+        bookmark: Option<Vec<u8>>,
+        revision: String,
+    },
+    /// jj's working-copy commit, `@`.
+    Jj {
+        change: String,
+        bookmarks: Vec<String>,
+        /// Bookmarks on `@-`, which is where a bookmark usually is while working on top of it.
+        parent_bookmarks: Vec<String>,
+        conflict: bool,
+    },
+}
+
+/// How many commits are to be squashed away; see `Backend::unsquashed`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Unsquashed {
+    pub count: u32,
+    /// Whether there were more unpushed commits than were looked through, so there may be more
+    /// than `count`.
+    pub more: bool,
+}
+
+impl fmt::Display for Unsquashed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.count, if self.more { "+" } else { "" })
+    }
+}
+
+pub struct Lock {
+    /// Relative to the state directory.
+    pub path: PathBuf,
+    /// When it was taken.
+    pub since: SystemTime,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Merge,
+    Rebase,
+    /// Applying patches from a mailbox.
+    Am,
+    CherryPick,
+    Revert,
+    Bisect,
+    Histedit,
+}
+
+impl Operation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operation::Merge => "MERGING",
+            Operation::Rebase => "REBASE",
+            Operation::Am => "AM",
+            Operation::CherryPick => "CHERRY-PICKING",
+            Operation::Revert => "REVERTING",
+            Operation::Bisect => "BISECTING",
+            Operation::Histedit => "HISTEDIT",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Operation> {
+        [
+            Operation::Merge,
+            Operation::Rebase,
+            Operation::Am,
+            Operation::CherryPick,
+            Operation::Revert,
+            Operation::Bisect,
+            Operation::Histedit,
+        ]
+        .into_iter()
+        .find(|op| op.as_str() == s)
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The `key = value` settings of an INI-style config file, along with the `[section]` each is in.
+/// Good enough for both git's config and Mercurial's hgrc, so long as nobody's being clever.
+pub fn config_entries(contents: &str) -> impl Iterator<Item = (&str, &str, &str)> {
+    // Split only around ASCII, so each part is as much UTF-8 as the whole was.
+    let utf8 = |b| std::str::from_utf8(b).unwrap();
+    config_entries_bytes(contents.as_bytes()).map(move |(s, k, v)| (utf8(s), utf8(k), utf8(v)))
+}
+
+/// `config_entries`, for a config that may name things (branches, say) that aren't UTF-8.
+pub fn config_entries_bytes(contents: &[u8]) -> impl Iterator<Item = (&[u8], &[u8], &[u8])> {
+    let mut section: &[u8] = b"";
+    contents.split(|&b| b == b'\n').filter_map(move |line| {
+        let line = line.trim_ascii();
+        if let Some(name) = line.strip_prefix(b"[").and_then(|l| l.strip_suffix(b"]")) {
+            section = name.trim_ascii();
+            return None;
+        }
+        if line.starts_with(b"#") || line.starts_with(b";") {
+            return None;
+        }
+        let equals = line.iter().position(|&b| b == b'=')?;
+        let (key, value) = (&line[..equals], &line[equals + 1..]);
+        Some((section, key.trim_ascii(), value.trim_ascii()))
+    })
+}
+
+/// Finds the innermost repository `dir` is in, and the backend it belongs to.
+pub fn discover(
+    dir: &Path,
+    deadline: Instant,
+) -> Result<Option<(&'static dyn Backend, PathBuf)>, TimedOut> {
+    let mut found: Option<(&'static dyn Backend, PathBuf)> = None;
+    for backend in BACKENDS {
+        if let Some(root) = backend.discover(dir, deadline)? {
+            // Both roots contain `dir`, so the longer one is inside the other.
+            let inner = match &found {
+                Some((_, other)) => other.as_os_str().len() < root.as_os_str().len(),
+                None => true,
+            };
+            if inner {
+                found = Some((*backend, root));
+            }
+        }
+    }
+    Ok(found)
+}
@@ -0,0 +1,535 @@
+//! Watches a repository with inotify, so we know when the cached prompt state went stale.
+//!
+//! The watcher runs on a background thread, and shares no locks with the rest of the module: the
+//! prompt itself renders in a forked `$(…)` subshell, and a lock held by another thread at the
+//! moment of the `fork()` would never be released in the child. It's told what to watch over a
+//! pipe, and reports back through atomics.
+//!
+//! Directories the repository ignores (`target/`, `node_modules/`) aren't watched: what happens in
+//! them doesn't change what's shown, and there can be a great many of them.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::fd::{AsFd, AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::poll::{PollFd, PollFlags, PollTimeout};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+
+use crate::vcs::Kind;
+
+/// inotify watches are per-directory, and a kernel resource. If the targets have more directories
+/// than this between them, we stop adding watches, and report the ones left out as always stale.
+const MAX_WATCHES: usize = 8192;
+
+/// How long the watcher thread waits on the backend to say which directories are ignored.
+const IGNORED_BUDGET: Duration = Duration::from_secs(1);
+
+/// Version control state directories, which are never watched as part of a worktree: they're
+/// either the target's own (and watched selectively), or belong to a nested repository.
+const STATE_DIRS: &[&str] = &[".git", ".hg", ".jj"];
+
+fn is_state_dir(name: &OsStr) -> bool {
+    STATE_DIRS.iter().any(|d| OsStr::new(d) == name)
+}
+
+/// A directory of a repository's own state; see `Backend::state_dirs`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct StateDir {
+    pub path: PathBuf,
+    /// Whether the directories under it are watched too.
+    pub recursive: bool,
+}
+
+impl StateDir {
+    pub fn new(path: &Path) -> StateDir {
+        StateDir {
+            path: path.to_owned(),
+            recursive: false,
+        }
+    }
+
+    pub fn recursive(path: &Path) -> StateDir {
+        StateDir {
+            path: path.to_owned(),
+            recursive: true,
+        }
+    }
+}
+
+/// What a watch is pointed at.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Target {
+    /// Whose repository it is, which says what in the worktree is ignored.
+    pub kind: Kind,
+    pub state_dirs: Vec<StateDir>,
+    pub worktree: PathBuf,
+}
+
+/// What's known of one target: set by the thread, and taken by whoever asked for the watch.
+struct Flags {
+    dirty: AtomicBool,
+    /// Set when we couldn't cover the whole target; changes might go unseen.
+    incomplete: AtomicBool,
+}
+
+impl Flags {
+    /// From here on, nothing that wasn't seen can be trusted.
+    fn give_up(&self) {
+        self.incomplete.store(true, Ordering::SeqCst);
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+}
+
+/// One target being watched, as `Watcher::watch` hands them out.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WatchId(u64);
+
+/// What the thread is told, over the pipe: each message is boxed, and the box's address is
+/// written, which at a pointer's size, the pipe passes on whole.
+enum Message {
+    Watch(WatchId, Target, Arc<Flags>),
+    Unwatch(WatchId),
+    Stop,
+}
+
+const MESSAGE_SIZE: usize = std::mem::size_of::<usize>();
+
+/// Sends `message` down the pipe; `false` if it couldn't be, because the thread is that far
+/// behind, or gone.
+fn send(pipe: &OwnedFd, message: Message) -> bool {
+    let message = Box::into_raw(Box::new(message));
+    let address = (message as usize).to_ne_bytes();
+    match nix::unistd::write(pipe.as_fd(), &address) {
+        Ok(MESSAGE_SIZE) => true,
+        _ => {
+            drop(unsafe { Box::from_raw(message) });
+            false
+        }
+    }
+}
+
+/// Takes the next message from the pipe, if there's one waiting.
+fn receive(pipe: &OwnedFd) -> Option<Message> {
+    let mut address = [0u8; MESSAGE_SIZE];
+    loop {
+        match nix::unistd::read(pipe.as_raw_fd(), &mut address) {
+            Ok(MESSAGE_SIZE) => {
+                let message = usize::from_ne_bytes(address) as *mut Message;
+                return Some(*unsafe { Box::from_raw(message) });
+            }
+            Err(Errno::EINTR) => continue,
+            // Never short: what was written whole is read whole.
+            _ => return None,
+        }
+    }
+}
+
+/// Watches any number of targets, with the one thread and the one inotify instance: those are
+/// limited too (to 128 instances a user, by default).
+pub struct Watcher {
+    /// The thread reads messages from the one end, and we write them to the other.
+    messages: (Arc<OwnedFd>, OwnedFd),
+    watches: HashMap<WatchId, Arc<Flags>>,
+    next_id: u64,
+    /// Set once the thread can't be relied on: it gave up, or missed a message.
+    broken: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watcher {
+    pub fn start() -> anyhow::Result<Watcher> {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)?;
+        let (rx, tx) = nix::unistd::pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+        let rx = Arc::new(rx);
+        let broken = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let rx = rx.clone();
+            let broken = broken.clone();
+            std::thread::Builder::new()
+                .name("prompt-watch".to_owned())
+                .spawn(move || watch_thread(inotify, &rx, &broken))?
+        };
+        Ok(Watcher {
+            messages: (rx, tx),
+            watches: HashMap::new(),
+            next_id: 0,
+            broken,
+            thread: Some(thread),
+        })
+    }
+
+    /// Starts watching `target`. It isn't clean until the thread has set it up.
+    pub fn watch(&mut self, target: Target) -> WatchId {
+        let id = WatchId(self.next_id);
+        self.next_id += 1;
+        let flags = Arc::new(Flags {
+            dirty: AtomicBool::new(true),
+            incomplete: AtomicBool::new(false),
+        });
+        if !send(&self.messages.1, Message::Watch(id, target, flags.clone())) {
+            self.broken.store(true, Ordering::SeqCst);
+        }
+        self.watches.insert(id, flags);
+        id
+    }
+
+    pub fn unwatch(&mut self, id: WatchId) {
+        if self.watches.remove(&id).is_some() && !send(&self.messages.1, Message::Unwatch(id)) {
+            // Its watches are left behind, taking up room.
+            self.broken.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns `true` if nothing under the target changed since the last call.
+    pub fn take_clean(&self, id: WatchId) -> bool {
+        let Some(flags) = self.watches.get(&id) else {
+            return false;
+        };
+        let dirty = flags.dirty.swap(false, Ordering::SeqCst);
+        !dirty && !flags.incomplete.load(Ordering::SeqCst) && !self.broken.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        send(&self.messages.1, Message::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        // Whatever the thread left unread (if it gave up early, say) is ours to free.
+        while receive(&self.messages.0).is_some() {}
+    }
+}
+
+/// A target, as the thread has it.
+struct Watched {
+    flags: Arc<Flags>,
+    kind: Kind,
+    worktree: PathBuf,
+    /// The directories watched along with everything beneath them: the worktree, and any state
+    /// directories that are watched that way.
+    recursive: Vec<PathBuf>,
+    /// Directories in the worktree that the repository ignores, as of when we started watching it.
+    ignored: Vec<PathBuf>,
+}
+
+impl Watched {
+    /// Whether a directory that turns up in `parent` is part of this target.
+    fn covers(&self, parent: &Path) -> bool {
+        // The worktree's state directories are under it, but they aren't part of it.
+        self.recursive.iter().any(|r| {
+            parent
+                .strip_prefix(r)
+                .is_ok_and(|rel| !rel.iter().any(is_state_dir))
+        })
+    }
+
+    /// Whether `dir`, a directory that's just turned up in the worktree, is one the repository
+    /// ignores.
+    fn is_ignored(&self, dir: &Path) -> bool {
+        // A state directory's own (under `.git/refs/`, say) are never ignored.
+        let prefix = match dir.strip_prefix(&self.worktree) {
+            Ok(prefix) if !prefix.iter().any(is_state_dir) => prefix,
+            _ => return false,
+        };
+        let deadline = Instant::now() + IGNORED_BUDGET;
+        self.kind
+            .backend()
+            .ignored(&self.worktree, prefix, deadline)
+            .unwrap_or(false)
+    }
+}
+
+/// A watched directory, and the targets it's watched for: nested repositories share some, and
+/// inotify gives a directory the one watch however many times it's added.
+struct Dir {
+    path: PathBuf,
+    ids: Vec<WatchId>,
+}
+
+struct Dirs {
+    inotify: Inotify,
+    by_wd: HashMap<WatchDescriptor, Dir>,
+}
+
+impl Dirs {
+    fn add(&mut self, dir: &Path, id: WatchId, flags: &Flags) -> bool {
+        if MAX_WATCHES <= self.by_wd.len() {
+            flags.incomplete.store(true, Ordering::SeqCst);
+            return false;
+        }
+        let mask = AddWatchFlags::IN_MODIFY
+            | AddWatchFlags::IN_CLOSE_WRITE
+            | AddWatchFlags::IN_ATTRIB
+            | AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_DELETE
+            | AddWatchFlags::IN_MOVED_FROM
+            | AddWatchFlags::IN_MOVED_TO
+            | AddWatchFlags::IN_DELETE_SELF
+            | AddWatchFlags::IN_MOVE_SELF
+            | AddWatchFlags::IN_ONLYDIR
+            | AddWatchFlags::IN_DONT_FOLLOW;
+        match self.inotify.add_watch(dir, mask) {
+            Ok(wd) => {
+                let dir = self.by_wd.entry(wd).or_insert_with(|| Dir {
+                    path: dir.to_owned(),
+                    ids: Vec::new(),
+                });
+                if !dir.ids.contains(&id) {
+                    dir.ids.push(id);
+                }
+                true
+            }
+            // It's gone already, and there's nothing under it to miss.
+            Err(Errno::ENOENT | Errno::ENOTDIR) => false,
+            // Out of watches (`max_user_watches`), or some such: what's under it will go unseen.
+            Err(_) => {
+                flags.incomplete.store(true, Ordering::SeqCst);
+                false
+            }
+        }
+    }
+
+    /// Watches `dir` and every directory beneath it, except for version control state, and what
+    /// the repository ignores.
+    fn add_recursive(&mut self, dir: &Path, id: WatchId, target: &Watched) {
+        let mut stack = vec![dir.to_owned()];
+        while let Some(dir) = stack.pop() {
+            if !self.add(&dir, id, &target.flags) {
+                continue;
+            }
+            let entries = match fs::read_dir(&dir) {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                if is_dir && !is_state_dir(&entry.file_name()) {
+                    let path = entry.path();
+                    if !target.ignored.contains(&path) {
+                        stack.push(path);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stops watching for `id`, and drops the watches nothing else needs.
+    fn remove(&mut self, id: WatchId) {
+        self.by_wd.retain(|wd, dir| {
+            dir.ids.retain(|i| *i != id);
+            if dir.ids.is_empty() {
+                let _ = self.inotify.rm_watch(*wd);
+            }
+            !dir.ids.is_empty()
+        });
+    }
+}
+
+struct Watches {
+    dirs: Dirs,
+    targets: HashMap<WatchId, Watched>,
+}
+
+impl Watches {
+    fn watch(&mut self, id: WatchId, target: Target, flags: Arc<Flags>) {
+        let deadline = Instant::now() + IGNORED_BUDGET;
+        // If the backend can't say, everything's watched; that's slower, but no less right.
+        let ignored = target
+            .kind
+            .backend()
+            .ignored_dirs(&target.worktree, deadline)
+            .unwrap_or_default();
+        let mut watched = Watched {
+            flags,
+            kind: target.kind,
+            worktree: target.worktree.clone(),
+            recursive: Vec::new(),
+            ignored,
+        };
+        for dir in target.state_dirs {
+            if dir.recursive {
+                self.dirs.add_recursive(&dir.path, id, &watched);
+                watched.recursive.push(dir.path);
+            } else {
+                self.dirs.add(&dir.path, id, &watched.flags);
+            }
+        }
+        self.dirs.add_recursive(&target.worktree, id, &watched);
+        watched.recursive.push(target.worktree);
+        // Anything that changed while we were setting up went unseen.
+        watched.flags.dirty.store(true, Ordering::SeqCst);
+        self.targets.insert(id, watched);
+    }
+
+    fn unwatch(&mut self, id: WatchId) {
+        self.dirs.remove(id);
+        self.targets.remove(&id);
+    }
+
+    fn clear(&mut self) {
+        for id in self.targets.keys().copied().collect::<Vec<_>>() {
+            self.unwatch(id);
+        }
+    }
+
+    /// Marks every target as stale, for good.
+    fn give_up(&self) {
+        for target in self.targets.values() {
+            target.flags.give_up();
+        }
+    }
+
+    fn handle(&mut self, event: InotifyEvent) {
+        if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+            // Events were dropped, and with them, any directories that were created: those will
+            // never get watches of their own. Until they're watched afresh, nothing can be
+            // trusted.
+            self.give_up();
+            return;
+        }
+        if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+            self.dirs.by_wd.remove(&event.wd);
+            return;
+        }
+        let Some(dir) = self.dirs.by_wd.get(&event.wd) else {
+            return;
+        };
+        let (parent, ids) = (dir.path.clone(), dir.ids.clone());
+        for id in &ids {
+            self.targets[id].flags.dirty.store(true, Ordering::SeqCst);
+        }
+        // New directories in the worktree (or under refs/, say) need watches of their own.
+        let new_dir = event.mask.contains(AddWatchFlags::IN_ISDIR)
+            && event
+                .mask
+                .intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO);
+        let Some(name) = event.name.filter(|name| new_dir && !is_state_dir(name)) else {
+            return;
+        };
+        let dir = parent.join(name);
+        for id in ids {
+            let target = &self.targets[&id];
+            if target.covers(&parent) && !target.is_ignored(&dir) {
+                self.dirs.add_recursive(&dir, id, target);
+            }
+        }
+    }
+}
+
+fn watch_thread(inotify: Inotify, messages: &OwnedFd, broken: &AtomicBool) {
+    let mut watches = Watches {
+        dirs: Dirs {
+            inotify,
+            by_wd: HashMap::new(),
+        },
+        targets: HashMap::new(),
+    };
+
+    loop {
+        let (inotify_ready, messages_ready) = {
+            let mut fds = [
+                PollFd::new(watches.dirs.inotify.as_fd(), PollFlags::POLLIN),
+                PollFd::new(messages.as_fd(), PollFlags::POLLIN),
+            ];
+            match nix::poll::poll(&mut fds, PollTimeout::NONE) {
+                Ok(_) => (),
+                Err(Errno::EINTR) => continue,
+                Err(_) => break,
+            }
+            let ready = |fd: &PollFd| fd.revents().is_some_and(|r| !r.is_empty());
+            (ready(&fds[0]), ready(&fds[1]))
+        };
+
+        if messages_ready {
+            while let Some(message) = receive(messages) {
+                match message {
+                    Message::Watch(id, target, flags) => watches.watch(id, target, flags),
+                    Message::Unwatch(id) => watches.unwatch(id),
+                    Message::Stop => {
+                        watches.clear();
+                        return;
+                    }
+                }
+            }
+        }
+
+        if inotify_ready {
+            let events = match watches.dirs.inotify.read_events() {
+                Ok(e) => e,
+                Err(Errno::EAGAIN) => continue,
+                Err(_) => break,
+            };
+            for event in events {
+                watches.handle(event);
+            }
+        }
+    }
+
+    // The thread can't go on: from here on, nothing is clean.
+    broken.store(true, Ordering::SeqCst);
+    watches.give_up();
+    watches.clear();
+}
+
+/// The shell's watcher, and what it's watching: the repository the shell is in, if any.
+struct ShellWatcher {
+    watcher: Watcher,
+    current: Option<(Target, WatchId)>,
+}
+
+/// Only present while `watch` is enabled.
+static WATCHER: Mutex<Option<ShellWatcher>> = Mutex::new(None);
+
+pub fn set_enabled(enabled: bool) -> anyhow::Result<()> {
+    let mut lock = WATCHER.lock().unwrap();
+    if enabled {
+        if lock.is_none() {
+            *lock = Some(ShellWatcher {
+                watcher: Watcher::start()?,
+                current: None,
+            });
+        }
+    } else {
+        *lock = None;
+    }
+    Ok(())
+}
+
+/// Points the shell's watcher at a different repository (or at nothing). A no-op if the target is
+/// unchanged.
+pub fn retarget(target: Option<Target>) {
+    let mut lock = WATCHER.lock().unwrap();
+    let Some(shell) = lock.as_mut() else {
+        return;
+    };
+    if shell.current.as_ref().map(|(t, _)| t) == target.as_ref() {
+        return;
+    }
+    if let Some((_, id)) = shell.current.take() {
+        shell.watcher.unwatch(id);
+    }
+    if let Some(target) = target {
+        let id = shell.watcher.watch(target.clone());
+        shell.current = Some((target, id));
+    }
+}
+
+/// `true` if a watcher is running and has seen no changes since the last call.
+pub fn take_clean() -> bool {
+    match WATCHER.lock().unwrap().as_ref() {
+        Some(ShellWatcher {
+            watcher,
+            current: Some((_, id)),
+        }) => watcher.take_clean(*id),
+        _ => false,
+    }
+}
@@ -0,0 +1,206 @@
+//! How much room a prompt takes up on the screen, once zsh has expanded it.
+//!
+//! Colors and other attributes take up no room, nor does anything in `%{…%}` (save for what `%G`
+//! says it does). What the rest of zsh's escapes expand to is up to the caller, who knows the
+//! values; characters are as wide as Unicode says, so a CJK character or an emoji takes two
+//! columns.
+
+use std::fmt::Write as _;
+
+use unicode_width::UnicodeWidthChar;
+
+/// The width of each line of `prompt`. `escape` gives the width of what `%c` expands to, for the
+/// escapes that stand for some value or other (`%n`, `%~`, `%*`, …).
+pub fn lines(prompt: &str, escape: &dyn Fn(char) -> usize) -> Vec<usize> {
+    let mut widths = vec![0];
+    let mut chars = prompt.chars().peekable();
+    while let Some(c) = chars.next() {
+        let width = widths.last_mut().unwrap();
+        match c {
+            '\n' => widths.push(0),
+            '%' => {
+                // A numeric argument, as in `%3~`, which makes no odds to us.
+                while chars.next_if(|c| c.is_ascii_digit() || *c == '-').is_some() {}
+                match chars.next() {
+                    None => *width += 1,
+                    Some('%' | ')') => *width += 1,
+                    Some('{') => *width += invisible(&mut chars),
+                    // Colors, with their `{…}` argument.
+                    Some('F' | 'K') => {
+                        if chars.peek() == Some(&'{') {
+                            chars.find(|&c| c == '}');
+                        }
+                    }
+                    // Truncation, up to the next `<` (or `>`); as it can only make things
+                    // narrower, leave it be.
+                    Some(end @ ('<' | '>')) => {
+                        chars.find(|&c| c == end);
+                    }
+                    Some('B' | 'b' | 'U' | 'u' | 'S' | 's' | 'f' | 'k' | 'E') => {}
+                    Some('D') if chars.peek() == Some(&'{') => {
+                        let format: String =
+                            chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+                        // chrono doesn't know zsh's own (`%K`, `%L`, `%.`), and says so by failing
+                        // the write; then the format itself is as good a guess as any.
+                        let mut date = String::new();
+                        if write!(date, "{}", chrono::Local::now().format(&format)).is_err() {
+                            date = format;
+                        }
+                        *width += str_width(&date);
+                    }
+                    Some('(') => {
+                        let (shown, hidden) = ternary(&mut chars);
+                        // We can't tell which it'll be; count on the wider one.
+                        let widest = |s: &str| lines(s, escape).into_iter().max().unwrap_or(0);
+                        *width += widest(&shown).max(widest(&hidden));
+                    }
+                    Some(c) => *width += escape(c),
+                }
+            }
+            c => *width += c.width().unwrap_or(0),
+        }
+    }
+    widths
+}
+
+fn str_width(s: &str) -> usize {
+    s.chars().map(|c| c.width().unwrap_or(0)).sum()
+}
+
+/// Skips to the end of a `%{…%}`, which takes up no room, save for the room `%G` says it does.
+fn invisible(chars: &mut impl Iterator<Item = char>) -> usize {
+    let mut width = 0;
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            continue;
+        }
+        let mut arg = String::new();
+        for c in chars.by_ref() {
+            match c {
+                '0'..='9' => arg.push(c),
+                '}' => return width,
+                'G' => {
+                    width += arg.parse().unwrap_or(1);
+                    break;
+                }
+                _ => break,
+            }
+        }
+    }
+    width
+}
+
+/// Reads the rest of a `%(x.shown.hidden)`, after the `%(`: what it shows when `x` holds, and
+/// what it shows when it doesn't.
+fn ternary(chars: &mut impl Iterator<Item = char>) -> (String, String) {
+    // The test: an optional number, and a letter.
+    let mut chars = chars.skip_while(|c| c.is_ascii_digit() || *c == '-');
+    chars.next();
+    let Some(separator) = chars.next() else {
+        return (String::new(), String::new());
+    };
+    let mut branches = [String::new(), String::new()];
+    let mut branch = 0;
+    let mut depth = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => {
+                branches[branch].push(c);
+                for c in chars.by_ref() {
+                    branches[branch].push(c);
+                    if c == '(' {
+                        depth += 1;
+                    }
+                    if !(c.is_ascii_digit() || c == '-') {
+                        break;
+                    }
+                }
+                continue;
+            }
+            ')' if depth == 0 => break,
+            ')' => depth -= 1,
+            c if c == separator && depth == 0 && branch == 0 => {
+                branch = 1;
+                continue;
+            }
+            _ => {}
+        }
+        branches[branch].push(c);
+    }
+    let [shown, hidden] = branches;
+    (shown, hidden)
+}
+
+/// `$COLUMNS`, or if that's not to be had, the usual 80.
+pub fn columns() -> usize {
+    unsafe { crate::zsh::get_string_param(c"COLUMNS") }
+        .and_then(|c| c.to_str().ok())
+        .and_then(|c| c.parse::<usize>().ok())
+        .unwrap_or(80)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stand-in for what zsh knows: `%n` is a 4-letter name, and the rest are nothing.
+    fn widths(prompt: &str) -> Vec<usize> {
+        lines(prompt, &|c| if c == 'n' { 4 } else { 0 })
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(widths(""), [0]);
+        assert_eq!(widths("ab\ncde\n"), [2, 3, 0]);
+        assert_eq!(widths("%n@host %%"), [11]);
+        assert_eq!(widths("%3~"), [0]);
+    }
+
+    #[test]
+    fn wide_characters() {
+        assert_eq!(widths("日本"), [4]);
+        assert_eq!(widths("🙂 x"), [4]);
+        assert_eq!(widths("e\u{301}"), [1]);
+    }
+
+    #[test]
+    fn invisible() {
+        assert_eq!(widths("%{\x1b[1m%}bold%{\x1b[0m%}"), [4]);
+        // Save for what `%G` says.
+        assert_eq!(widths("%{🙂%2G%}x"), [3]);
+        assert_eq!(widths("%{🙂%G%}x"), [2]);
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(widths("%F{red}red%f%K{#123456}%k%B%b"), [3]);
+        assert_eq!(widths("%1F%3Kx%f%k"), [1]);
+    }
+
+    #[test]
+    fn ternaries() {
+        // Whichever is wider.
+        assert_eq!(widths("%(?.ok.failed)"), [6]);
+        assert_eq!(widths("%(1j.%n jobs.)"), [9]);
+        // `%)` is a `)`, rather than the end.
+        assert_eq!(widths("%(?.(ok%).no)!"), [5]);
+        assert_eq!(widths("%(?.a%(!.bb.c).d)"), [3]);
+        // The separator is whatever follows the test.
+        assert_eq!(widths("%(?/a.b/c)"), [3]);
+    }
+
+    #[test]
+    fn truncation() {
+        // It can only make things narrower, so the whole is counted.
+        assert_eq!(widths("%5<..<%n/path%<<"), [9]);
+        assert_eq!(widths("%20>…>short%>>"), [5]);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(widths("%D{%H:%M}"), [5]);
+        // zsh's own, which chrono doesn't know; they're guessed at, rather than a panic.
+        assert_eq!(widths("%D{%K:%L}"), [5]);
+        assert_eq!(widths("%D{%S.%.}"), [5]);
+    }
+}
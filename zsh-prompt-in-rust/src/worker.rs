@@ -0,0 +1,103 @@
+//! Runs slow work off of zsh's thread, and tells zle about it when it's done.
+//!
+//! Each worker gets an eventfd, which the worker thread signals when its result is ready. We hand
+//! the fd to `zle -F`, which calls our handler widget once it becomes readable; the widget calls
+//! back into the module to collect the result, and then re-renders the prompt.
+
+use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The widget `zle -F` invokes; see `define_handler`.
+const HANDLER: &str = "_rust-prompt-alpha_async-handler";
+
+pub struct Worker<T> {
+    event_fd: Arc<OwnedFd>,
+    rx: Receiver<T>,
+    registered: bool,
+}
+
+impl<T: Send + 'static> Worker<T> {
+    pub fn spawn(f: impl FnOnce() -> T + Send + 'static) -> anyhow::Result<Worker<T>> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let event_fd = Arc::new(unsafe { OwnedFd::from_raw_fd(fd) });
+        let (tx, rx) = mpsc::channel();
+        {
+            // The worker holds its own reference to the eventfd, so it is never writing to a
+            // closed fd, even if we've since given up on it.
+            let event_fd = event_fd.clone();
+            std::thread::Builder::new()
+                .name("prompt-worker".to_owned())
+                .spawn(move || {
+                    let result = f();
+                    if tx.send(result).is_ok() {
+                        let one = 1u64.to_ne_bytes();
+                        let _ = nix::unistd::write(&*event_fd, &one);
+                    }
+                })?;
+        }
+        Ok(Worker {
+            event_fd,
+            rx,
+            registered: false,
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.event_fd.as_raw_fd()
+    }
+
+    /// Asks zle to call our handler widget once the result is ready.
+    pub fn register(&mut self) {
+        if self.registered {
+            return;
+        }
+        zle_fd_handler(self.fd(), Some(HANDLER));
+        self.registered = true;
+    }
+
+    /// Waits up to `timeout` for the worker to finish.
+    pub fn wait(&self, timeout: Duration) -> Option<T> {
+        self.rx.recv_timeout(timeout).ok()
+    }
+
+    /// Returns the result, if the worker has finished.
+    pub fn try_take(&self) -> Option<T> {
+        let mut buf = [0u8; 8];
+        let _ = nix::unistd::read(self.fd(), &mut buf);
+        self.rx.try_recv().ok()
+    }
+}
+
+impl<T> Drop for Worker<T> {
+    fn drop(&mut self) {
+        if self.registered {
+            zle_fd_handler(self.event_fd.as_raw_fd(), None);
+        }
+    }
+}
+
+/// Installs (or, with `None`, removes) a `zle -F` handler for `fd`.
+pub fn zle_fd_handler(fd: RawFd, handler: Option<&str>) {
+    let command = match handler {
+        Some(h) => format!("zle -F -w {fd} {h}"),
+        None => format!("zle -F {fd}"),
+    };
+    let command = CString::new(command).unwrap();
+    unsafe { crate::zsh::exec_string(&command) }
+}
+
+/// Defines the widget `zle -F` calls when a worker finishes.
+pub fn define_handler() {
+    let command = format!(
+        "function {HANDLER}() {{ _rust-prompt-alpha_async-done \"$1\" && zle reset-prompt }}\n\
+         zle -N {HANDLER}"
+    );
+    let command = CString::new(command).unwrap();
+    unsafe { crate::zsh::exec_string(&command) }
+}
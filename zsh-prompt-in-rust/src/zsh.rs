@@ -1,5 +1,6 @@
-use std::ffi::{CStr, CString};
-use std::ptr::null_mut;
+use std::ffi::{CStr, CString, OsString};
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
 
 use libc::{c_char, c_int};
 use smallvec::SmallVec;
@@ -68,7 +69,27 @@ pub struct HashNode {
     pub flags: c_int,
 }
 
+#[repr(C)]
+pub struct HashTable {
+    pub hsize: c_int,
+    pub ct: c_int,
+    pub nodes: *mut *mut HashNode,
+    // The table's methods follow, which we've no need of.
+}
+
+#[repr(C)]
+pub struct NamedDir {
+    pub node: HashNode,
+    pub dir: *mut c_char,
+    pub diff: c_int,
+}
+
+/// `hash -d` won't use it to abbreviate a directory.
+const ND_NOABBREV: c_int = 1 << 2;
+
 extern "C" {
+    pub static nameddirtab: *mut HashTable;
+
     pub fn featuresarray(m: *mut Module, f: *mut Features) -> *mut *mut c_char;
 
     pub fn handlefeatures(m: *mut Module, f: *mut Features, enables: *mut *mut c_int) -> c_int;
@@ -76,6 +97,8 @@ extern "C" {
     pub fn setfeatureenables(m: *mut Module, f: *mut Features, e: *mut libc::c_int) -> c_int;
 
     pub fn getsparam(s: *mut c_char) -> *mut c_char;
+
+    pub fn execstring(s: *mut c_char, dont_change_job: c_int, exiting: c_int, context: *mut c_char);
 }
 
 /**
@@ -91,9 +114,72 @@ pub unsafe fn get_string_param(name: &CStr) -> Option<&'static CStr> {
         .map(|b| b as i8)
         .collect();
     let v = unsafe { getsparam(v.as_mut_ptr()) };
-    if v == null_mut() {
+    if v.is_null() {
         None
     } else {
         Some(unsafe { CStr::from_ptr(v) })
     }
 }
+
+/// A scalar parameter's value, as the bytes it stands for (that is, unmetafied); `None` if it isn't
+/// set.
+pub fn param_bytes(name: &CStr) -> Option<Vec<u8>> {
+    unsafe { get_string_param(name) }.map(unmetafy)
+}
+
+/// Runs `command` as shell code, as `eval` would.
+pub unsafe fn exec_string(command: &CStr) {
+    let mut command: Vec<_> = CString::from(command)
+        .into_bytes_with_nul()
+        .into_iter()
+        .map(|b| b as i8)
+        .collect();
+    let mut context: SmallVec<[_; 16]> = c"rust-prompt"
+        .to_bytes_with_nul()
+        .iter()
+        .map(|b| *b as i8)
+        .collect();
+    unsafe { execstring(command.as_mut_ptr(), 1, 0, context.as_mut_ptr()) }
+}
+
+/// zsh's named directories (`hash -d`, and `~user`s that have been used) that it would abbreviate
+/// a directory to, as (name, directory) pairs.
+pub unsafe fn named_dirs() -> Vec<(OsString, PathBuf)> {
+    let table = unsafe { nameddirtab };
+    if table.is_null() {
+        return Vec::new();
+    }
+    let table = unsafe { &*table };
+    let mut dirs = Vec::new();
+    for i in 0..table.hsize as usize {
+        let mut node = unsafe { *table.nodes.add(i) };
+        while !node.is_null() {
+            let named = unsafe { &*(node as *const NamedDir) };
+            if named.node.flags & ND_NOABBREV == 0 && !named.dir.is_null() {
+                let (name, dir) =
+                    unsafe { (CStr::from_ptr(named.node.nam), CStr::from_ptr(named.dir)) };
+                dirs.push((
+                    OsString::from_vec(unmetafy(name)),
+                    PathBuf::from(OsString::from_vec(unmetafy(dir))),
+                ));
+            }
+            node = named.node.next;
+        }
+    }
+    dirs
+}
+
+/// zsh keeps strings "metafied", with some bytes (NUL among them) escaped as `Meta` followed by the
+/// byte xor 32.
+pub fn unmetafy(s: &CStr) -> Vec<u8> {
+    const META: u8 = 0x83;
+    let mut bytes = s.to_bytes().iter();
+    let mut out = Vec::new();
+    while let Some(&b) = bytes.next() {
+        match b {
+            META => out.extend(bytes.next().map(|b| b ^ 32)),
+            b => out.push(b),
+        }
+    }
+    out
+}